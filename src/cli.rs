@@ -1,8 +1,10 @@
 use std::{collections::HashSet, fs::read_dir, path::PathBuf, process::exit, sync::OnceLock, time::Duration};
-use clap::{command, Parser};
+use clap::Parser;
 use base64::prelude::*;
+use regex::Regex;
 
-use crate::metrics::{CgroupVersion, DockerCgroupDriver};
+use crate::containers::Runtime;
+use crate::metrics::{CgroupVersion, CpuUnit, DockerCgroupDriver, KubepodsMode, MemoryUnit};
 
 #[derive(Parser, Clone, Debug)]
 #[command(version, about = "Simple Prometheus exporter for Docker container metrics. Use --help for more info.", long_about = "
@@ -19,13 +21,73 @@ Don't expect this tool to be perfect. Use `\x1b[36mcadvisor\x1b[0m` if you need
 battle-tested and with (much) more metrics.")]
 pub struct Cli {
     /// Path to the Docker "containers" directory
-    #[arg(short = 'd', long, default_value = "/var/lib/docker/containers/", env)]
-    pub containers_dir: PathBuf,
+    /// 
+    /// May be given multiple times (or separated with commas) for hosts with more than one Docker data root.
+    #[arg(short = 'd', long, default_value = "/var/lib/docker/containers/", value_delimiter = ',', env, verbatim_doc_comment)]
+    pub containers_dir: Vec<PathBuf>,
+
+    /// Name of the container config file inside each container's directory
+    #[arg(long, default_value = "config.v2.json", env)]
+    pub container_config_filename: String,
+
+    /// How many directory levels below --containers-dir the container config files are
+    /// 
+    /// Docker keeps them one level down (containers/<id>/config.v2.json). Increase this if your containers directory
+    /// has been reorganized and the config files live deeper.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..), env, verbatim_doc_comment)]
+    pub container_config_depth: u8,
+
+    /// Container engine to export the containers of
+    /// 
+    /// With podman, --containers-dir has to point at podman's overlay-containers directory (usually
+    /// /var/lib/containers/storage/overlay-containers/), and its containers.json is read instead of config files.
+    /// Podman keeps container labels and run state in its own database, so those aren't available. Only rootful
+    /// podman's cgroups are found by default; use --cgroup-recursive for rootless podman.
+    #[arg(long, value_enum, default_value_t = Runtime::Docker, env, verbatim_doc_comment)]
+    pub runtime: Runtime,
 
     /// Path to the cgroupfs
     #[arg(short = 'c', long, default_value = "/sys/fs/cgroup/", env)]
     pub cgroupfs_dir: PathBuf,
 
+    /// Path to the procfs, for network and scheduling metrics
+    /// 
+    /// If running this program in a container, this has to be the host's /proc (e.g. bind-mounted at /host/proc),
+    /// and the container needs to be in the host's PID namespace or have the host's /proc mounted.
+    #[arg(long, default_value = "/proc/", env, verbatim_doc_comment)]
+    pub procfs_dir: PathBuf,
+
+    /// Don't export network metrics
+    /// 
+    /// These are read from /proc/<pid>/net/dev for a process of each container, which is a few extra files to read
+    /// per container.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub disable_network_metrics: bool,
+
+    /// Export scheduling metrics (CPU run queue and I/O wait time) read from /proc
+    /// 
+    /// These are added up over every thread of every process in each container, so they're a lot more reading
+    /// than the other metrics on busy hosts, and off by default. I/O wait time needs the kernel's delay accounting
+    /// (delayacct boot parameter or the kernel.task_delayacct sysctl), otherwise it stays at 0.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub enable_sched_metrics: bool,
+
+    /// Export CPU usage per CPU core too (cgroup v1 only)
+    /// 
+    /// Adds container_cpu_percpu_seconds_total with a cpu label, from cpuacct.usage_percpu. That's one series per
+    /// core per container, so only turn this on for debugging CPU pinning or NUMA placement. cgroup v2 doesn't keep
+    /// per-CPU usage, so there this just logs a warning.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub percpu: bool,
+
+    /// Export counters without timestamps
+    /// 
+    /// Every sample normally carries the time it was read at. Counters (CPU time, bytes read and written...) are
+    /// exported with no timestamp in this mode, just the cumulative value the kernel reports, so the scraper's own
+    /// timestamps and counter reset handling apply. Gauges keep their timestamps.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub raw_counters: bool,
+
     /// IP and port to bind the HTTP server to
     /// 
     /// Defaults to localhost only. You must change this to be reachable over the network.
@@ -33,6 +95,54 @@ pub struct Cli {
     #[arg(short = 'l', long, default_value = "127.0.0.1:3000", env, verbatim_doc_comment)]
     pub listen_addr: core::net::SocketAddr,
 
+    /// URL path to serve metrics at
+    /// 
+    /// Metrics are always served at / as well, and single metric families at <path>/<family>.
+    #[arg(long, default_value = "/metrics", env, verbatim_doc_comment)]
+    pub metrics_path: String,
+
+    /// Number of threads to serve HTTP requests (and so scrapes) on
+    /// 
+    /// By default, everything runs on a single thread, which is plenty for one Prometheus scraping every few seconds.
+    /// With several scrapers on a big host, concurrent scrapes end up waiting on each other; set this to spread
+    /// them out over multiple cores.
+    ///     Set to 0 (the default) for the single-threaded mode.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub worker_threads: usize,
+
+    /// Maximum number of open HTTP connections
+    /// 
+    /// Connections beyond this are closed right after they're accepted, so a misbehaving client can't pile up
+    /// thousands of them. Prometheus only needs one per scrape target.
+    ///     Set to 0 (the default) for no limit.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub max_connections: usize,
+
+    /// Only read the cgroupfs for one scrape at a time
+    /// 
+    /// With several Prometheus servers (e.g. an HA pair) scraping at about the same time, each scrape would read
+    /// everything on its own. With this flag, scrapes wait for each other instead, and ones that were waiting on an
+    /// identical request just get its result. See dockerprom_serialized_scrapes_total for how often that happens.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub serialize_scrapes: bool,
+
+    /// Milliseconds to keep serving the same metrics for, instead of reading the cgroupfs again
+    /// 
+    /// With several Prometheus servers or a federation setup scraping this instance within the same second, there's
+    /// no point in reading everything for each of them. Only applies to identical requests for all metrics (not
+    /// to /metrics/<family>), and only successful scrapes are cached.
+    ///     Set to 0 (the default) to not cache.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub cache_ttl_ms: u64,
+
+    /// Milliseconds a scrape may take before giving up on it with a 503
+    /// 
+    /// A cgroupfs read that gets stuck would otherwise leave the scraper waiting until its own timeout. The default
+    /// matches Prometheus' default scrape timeout; there's no point in answering after that.
+    ///     Set to 0 to never time out.
+    #[arg(long, default_value_t = 10000, env, verbatim_doc_comment)]
+    pub scrape_timeout_ms: u64,
+
     /// Minimum milliseconds allowed between container metadata refreshes
     /// 
     /// When this program is queried for metrics, it will read the metrics for all Docker containers by container ID.
@@ -44,6 +154,64 @@ pub struct Cli {
     #[arg(skip)]
     pub min_metadata_refresh: Option<Duration>,
 
+    /// Milliseconds between background container metadata refreshes
+    /// 
+    /// By default, container metadata is only re-read when an unknown container ID is seen (see above). When this is
+    /// set, the metadata will also be re-read in the background on this interval, so that renamed or relabeled
+    /// containers get picked up too.
+    ///     Set to 0 (the default) to disable background refreshes.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub metadata_refresh_interval_ms: u32,
+
+    /// Maximum random milliseconds added to each background metadata refresh interval
+    /// 
+    /// When many exporters are started at the same time, their background refreshes (see above) will all hit the disk
+    /// at the same instant. This adds a random delay between 0 and this many milliseconds to each interval to spread
+    /// them out. Has no effect unless --metadata-refresh-interval-ms is set.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub refresh_jitter_ms: u32,
+
+    /// How many containers' metadata to keep before dropping that of removed containers
+    /// 
+    /// Metadata of containers that have since been removed is kept around until there's this much of it, then
+    /// everything that's no longer in --containers-dir is dropped on the next refresh.
+    #[arg(long, default_value_t = 2000, env, verbatim_doc_comment)]
+    pub max_metadata_entries: usize,
+
+    /// Watch --containers-dir for changes and re-read container metadata when it changes
+    /// 
+    /// Uses inotify, so metadata is up to date right after a container is created, renamed or removed, without
+    /// waiting for an unknown container ID to show up (and --min-metadata-refresh-ms). While the watch is working,
+    /// unknown container IDs don't trigger a refresh at all. If the watch fails, it falls back to that.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub watch_containers_dir: bool,
+
+    /// Only keep metadata for running containers
+    /// 
+    /// Every container Docker knows about has a config file under --containers-dir, including ones that stopped long
+    /// ago and have no cgroup anymore. With this flag, stopped containers are left out of the metadata that's kept
+    /// around, which keeps it smaller on hosts with lots of dead containers.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub only_running: bool,
+
+    /// Maximum size of a metrics response, in bytes
+    /// 
+    /// Safety valve for hosts that suddenly end up with a huge number of containers. If the metrics output would be
+    /// larger than this, whole metric families are left off the end of it, and dockerprom_response_truncated is set.
+    ///     Set to 0 (the default) for no limit.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub max_response_bytes: usize,
+
+    /// PEM file with the certificate (chain) to serve HTTPS with
+    /// 
+    /// Needs --tls-key too. Without these, the server speaks plain HTTP. They're only read at startup.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for --tls-cert
+    #[arg(long, env, verbatim_doc_comment)]
+    pub tls_key: Option<PathBuf>,
+
     /// HTTP Basic authentication credentials
     /// 
     /// By default, anyone can query this server for metrics. When this option is set, the client must send an HTTP
@@ -56,6 +224,84 @@ pub struct Cli {
     #[arg(skip)]
     pub basicauth_encoded: Option<String>,
 
+    /// HTTP Bearer token authentication
+    /// 
+    /// Like --basicauth, but the client must send an "Authorization: Bearer <token>" header with this token, which
+    /// is what Prometheus does with bearer_token or authorization in its scrape config. You cannot provide both this
+    /// and --basicauth.
+    /// 
+    /// We recommend setting this via environment variable:
+    #[arg(long, env, verbatim_doc_comment)]
+    pub bearer_token: Option<String>,
+    #[arg(skip)]
+    pub bearer_token_header: Option<String>,
+
+    /// How many times to retry reading a cgroup file after EINTR or EAGAIN
+    /// 
+    /// On busy hosts, reads from the cgroupfs are occasionally interrupted. Rather than dropping that container's
+    /// metrics for the scrape, the read is retried up to this many times.
+    #[arg(long, default_value_t = 3, env, verbatim_doc_comment)]
+    pub read_retries: u8,
+
+    /// How many levels deep to look for containers nested inside other containers
+    /// 
+    /// With sysbox, Docker in Docker and the like, the inner containers' cgroups live somewhere inside the outer
+    /// container's cgroup. When this is set, each container's cgroup is searched this many levels deep for them, and
+    /// they're reported with a parent_id label pointing at the outer container.
+    ///     Set to 0 (the default) to not look for nested containers.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub nested_cgroup_depth: u8,
+
+    /// Look for container cgroups anywhere in the cgroup tree instead of only where Docker puts them
+    /// 
+    /// Searches every controller's whole hierarchy for directories named <container id>, docker-<id>.scope or
+    /// libpod-<id>(.scope), down to --cgroup-max-depth levels. For hosts where Docker containers end up somewhere
+    /// unusual, like under kubepods.slice with cri-dockerd or in a custom cgroup parent. This reads a lot more
+    /// directories on every scrape than the default of looking in one place. --cgroup-path-template is ignored with
+    /// this.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub cgroup_recursive: bool,
+
+    /// How many levels deep --cgroup-recursive looks
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..), env, verbatim_doc_comment)]
+    pub cgroup_max_depth: u8,
+
+    /// Also export Kubernetes pods running on this host, per pod or per container in a pod
+    /// 
+    /// Containers in a pod each have their own cgroup inside the pod's cgroup (under kubepods or kubepods.slice).
+    /// With "pod", each pod is reported as a whole, with the pod UID as its id. With "container", each container
+    /// in a pod is reported separately. Either way, the pod_uid label holds the pod UID. Kubernetes doesn't keep its
+    /// containers' metadata in --containers-dir, so there are no name, image or container_label_ labels for these.
+    #[arg(long, default_value = "off", env, verbatim_doc_comment)]
+    pub kubepods: KubepodsMode,
+
+    /// Include the I/O of child cgroups inside each container in the blkio metrics
+    /// 
+    /// Containers that create cgroups of their own (systemd in a container, etc.) might do their I/O from those. On
+    /// cgroup v1 that I/O isn't counted by default; with this flag, the recursive stats are used instead (or the
+    /// container's cgroup subtree is added up by hand on kernels without them). On cgroup v2, the I/O stats already
+    /// include child cgroups, so this makes no difference.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub blkio_recursive_sum: bool,
+
+    /// Export block I/O metrics per device instead of summed up over all of them
+    /// 
+    /// Adds a device label (major:minor, like 8:0) to the container_blkio_ metrics, with one series per device the
+    /// container did I/O on. For hosts with several disks.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub blkio_per_device: bool,
+
+    /// Use device names (like sda) instead of major:minor numbers in device labels
+    /// 
+    /// Names are looked up in the sysfs (see --sysfs-dir) the first time a device shows up, and remembered after
+    /// that. Devices that can't be found there keep their number.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub blkio_device_names: bool,
+
+    /// Path to the sysfs, for --blkio-device-names
+    #[arg(long, default_value = "/sys/", env, verbatim_doc_comment)]
+    pub sysfs_dir: PathBuf,
+
     /// Override cgroup version detection
     /// 
     /// By default, this program will (crudely) analyze the cgroupfs file structure to try to guess whether cgroup
@@ -70,10 +316,88 @@ pub struct Cli {
     #[arg(long, env, verbatim_doc_comment)]
     pub docker_cgroup_driver: Option<DockerCgroupDriver>,
 
+    /// Where to find container cgroups, overriding the cgroup driver's usual layout
+    /// 
+    /// A path relative to the cgroupfs (or to each controller's directory on cgroup v1), with {id} where the
+    /// container ID goes, e.g. system.slice/docker-{id}.scope or kubepods.slice/custom-{id}. The {id} has to be
+    /// in the last path component. The directories before it can have * wildcards, for layouts with a parent per
+    /// pod or service, e.g. kubepods.slice/*/*/docker-{id}.scope. This is an escape hatch for setups the two cgroup
+    /// drivers don't cover.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub cgroup_path_template: Option<String>,
+
+    /// Unit for memory metrics
+    /// 
+    /// Bytes is the Prometheus convention and the default. With MiB, the memory metric names end in _mib instead.
+    #[arg(long, default_value = "bytes", env, verbatim_doc_comment)]
+    pub memory_unit: MemoryUnit,
+
+    /// Unit for CPU time metrics
+    /// 
+    /// Seconds is the Prometheus convention and the default. With milliseconds (ms), the CPU time metric names end
+    /// in _ms_total instead.
+    #[arg(long, default_value = "seconds", env, verbatim_doc_comment)]
+    pub cpu_unit: CpuUnit,
+
+    /// Add the container's command as a label on container_info
+    /// 
+    /// The entrypoint and command are joined with spaces and cut off after 128 characters. Think twice before
+    /// enabling this: commands often differ per container (more series) and some pass secrets as arguments, which
+    /// would then end up in your metrics.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub cmd_label: bool,
+
+    /// Prefix for the metric labels made from Docker labels
+    /// 
+    /// The default matches cadvisor. Change it if that clashes with another exporter, or you'd just like them shorter.
+    /// May be empty, in which case Docker labels that start with a digit are skipped.
+    #[arg(long, default_value = "container_label_", env, verbatim_doc_comment)]
+    pub label_prefix: String,
+
+    /// Sum up metrics over all containers with the same image, Compose project, or value of some Docker label
+    /// 
+    /// One of "image", "compose_project", or the key of a Docker label. Instead of a series per container, there's
+    /// one per image (or project, or label value) with only that label (plus --node-label and metric-specific
+    /// labels like device or interface), and the sum of all its containers' values. Gauges that can't be added
+    /// up (limits, specs, flags like container_running) get the largest value instead, and
+    /// container_start_time_seconds the earliest. For hosts with so many containers that per-container series are
+    /// too many. Containers without the label are grouped under "".
+    #[arg(long, env, verbatim_doc_comment)]
+    pub aggregate_by: Option<String>,
+    /// Metric label to aggregate under, and the Docker label its values come from (None for the image)
+    #[arg(skip)]
+    pub aggregate_labels: Option<(String, Option<String>)>,
+
+    /// Add a node label with this value to every container metric, or "auto" for this host's hostname
+    /// 
+    /// Useful when metrics from many hosts end up in one place (federation, remote write) without a per-target
+    /// label to tell them apart. The hostname is looked up once at startup, and is "unknown" if that fails.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub node_label: Option<String>,
+    #[arg(skip)]
+    pub node_name: Option<String>,
+
+    /// With --node-label auto, only use the hostname up to the first dot
+    #[arg(long, env, verbatim_doc_comment)]
+    pub node_label_strip_domain: bool,
+
+    /// Sort each series' labels by name
+    /// 
+    /// Docker labels otherwise come out in no particular order, which can change between restarts. Prometheus
+    /// doesn't care, but golden-file tests and diffs of the output do.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub deterministic_labels: bool,
+
+    /// Use the 12-character short container ID (like docker ps shows) in the id label
+    /// 
+    /// The full ID then goes in a full_id label instead, so nothing is lost.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub short_id: bool,
+
     /// Docker labels to ignore when labeling metrics
     /// 
     /// By default, all container metrics will be labelled with all the labels of the container (prefixed with
-    /// container_label_ and with dots replaced with underscores). This flag will exclude/ignore one or more
+    /// --label-prefix and with dots and such replaced with underscores). This flag will exclude/ignore one or more
     /// container labels during this process. You may provide the flag multiple times, or separate labels with
     /// commas. You cannot provide both this and --include-labels.
     #[arg(long, env, verbatim_doc_comment)]
@@ -91,6 +415,121 @@ pub struct Cli {
     #[arg(skip)]
     pub include_labels_set: HashSet<String>,
 
+    /// Only export metrics for containers whose name matches this regex
+    /// 
+    /// Handy with systematic naming conventions, e.g. ^prod- to only export production containers. The name is
+    /// matched without Docker's leading slash. Containers we have no metadata for (so no name) are left out.
+    #[arg(long, visible_alias = "name-filter", env, verbatim_doc_comment)]
+    pub name_regex_include: Option<String>,
+    #[arg(skip)]
+    pub name_regex_include_compiled: Option<Regex>,
+
+    /// Don't export metrics for containers whose name matches this regex
+    /// 
+    /// Works like --name-regex-include, but the other way around. If both are given, a container has to match the
+    /// include regex and not match the exclude regex.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub name_regex_exclude: Option<String>,
+    #[arg(skip)]
+    pub name_regex_exclude_compiled: Option<Regex>,
+
+    /// Only export metrics for containers with this Docker label, as key=value or just key for any value
+    /// 
+    /// You may provide the flag multiple times, or separate selectors with commas. A container has to match all of
+    /// them. Containers we have no metadata for (so no labels) are left out.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub label_selector: Vec<String>,
+    #[arg(skip)]
+    pub label_selectors: Vec<(String, Option<String>)>,
+
+    /// Containers to not export metrics for, by name or ID
+    /// 
+    /// Like with the docker CLI, IDs can be shortened to any prefix that's unique, e.g. the 12 characters
+    /// `docker ps` shows. An ambiguous prefix excludes nothing and logs a warning. You may provide the flag multiple
+    /// times, or separate containers with commas.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub exclude_container: Vec<String>,
+
+    /// Only export metrics for containers started less than this many milliseconds ago
+    /// 
+    /// Meant for debugging a rollout: with e.g. 600000, only containers (re)started in the last 10 minutes show up.
+    ///     Set to 0 (the default) to export containers regardless of when they started.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub since_ms: u64,
+
+    /// On startup, wait up to this many milliseconds for container cgroups to show up before detecting the setup
+    /// 
+    /// The cgroup version and Docker cgroup driver are detected once and then used for as long as the exporter runs.
+    /// Starting early on boot, before Docker has started any containers (or before the cgroup controllers are
+    /// mounted), can get them wrong. Waits until a directory that looks like a container cgroup exists, checking
+    /// twice a second. If none shows up in time, detection goes ahead anyway.
+    ///     Set to 0 (the default) to not wait.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub startup_wait_ms: u64,
+
+    /// Log a critical error if no scrape has been served for this many milliseconds
+    /// 
+    /// Guards against the exporter getting stuck (or nothing scraping it anymore). See also --watchdog-exit.
+    ///     Set to 0 (the default) to disable the watchdog.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub watchdog_timeout_ms: u64,
+
+    /// Exit when the watchdog fires, instead of only logging
+    /// 
+    /// Lets an orchestrator (Docker restart policy, systemd, ...) restart the exporter. Note that an exporter that
+    /// simply isn't being scraped will also be restarted.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub watchdog_exit: bool,
+
+    /// Exit after this many scrapes in a row failed
+    /// 
+    /// A scrape counts as failed if it returned an error, or if it found no containers at all even though Docker
+    /// says some are running (e.g. the cgroupfs became unreadable). Lets an orchestrator restart the exporter when
+    /// it's gotten into a bad state. Scrapes of a single metric family or with match[] are only counted if they
+    /// return an error.
+    ///     Set to 0 (the default) to never exit because of failed scrapes.
+    #[arg(long, default_value_t = 0, env, verbatim_doc_comment)]
+    pub max_consecutive_scrape_failures: u64,
+
+    /// Check the metrics output is valid before sending it
+    /// 
+    /// Every response is parsed again as Prometheus text format, and if anything's wrong with it (bad metric or label
+    /// names, bad quoting, duplicate labels or series...) a 500 is returned instead, with the details in the logs.
+    /// This costs some CPU per scrape, so it's mostly meant for staging or troubleshooting.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub validate_output: bool,
+
+    /// Check that metrics can be read, then exit instead of serving them
+    /// 
+    /// Does cgroup detection and one full scrape, then exits with 0 if it worked: if Docker has running containers,
+    /// at least one of them has to have been found in the cgroupfs. Otherwise it exits with 1 and says what's wrong.
+    /// Meant for init containers and CI, to fail early on hosts where detection gets it wrong.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub selftest: bool,
+
+    /// Print the metrics of one full scrape to stdout, then exit instead of serving them
+    /// 
+    /// Does cgroup detection and logs what it found, then scrapes once. Exits with 0 if that worked, or with 1 if
+    /// reading any metrics failed, even for one container. For trying out the flags on a host before pointing
+    /// Prometheus at it.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub dry_run: bool,
+
+    /// Enable the /debug/ endpoints
+    /// 
+    /// Currently that's just /debug/timings, which shows how long each collector took during its last run and how
+    /// many directories and files it went through. These sit behind the same authentication as the metrics.
+    /// This also adds the container_cgroup_depth metric, which helps find what's making scrapes slow.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub enable_debug_endpoints: bool,
+
+    /// Add a cgroup_path label to every metric (debugging aid)
+    /// 
+    /// The label holds the exact cgroup directory each value was read from, which helps when figuring out why a
+    /// container's metrics are wrong or missing. Leave this off normally; it adds a lot of label cardinality.
+    #[arg(long, env, verbatim_doc_comment)]
+    pub debug_cgroup_path_label: bool,
+
     /// Increase the log level (default is INFO, one is DEBUG, two is TRACE).
     /// 
     /// You can also use environment variable RUST_LOG={OFF, ERROR, WARN, INFO, DEBUG, TRACE}.
@@ -105,6 +544,12 @@ pub fn cfg() -> &'static Cli {
     CONFIG.get().unwrap()
 }
 
+/// Sets the config to the defaults, as if run without any arguments, for unit tests that need [cfg].
+#[cfg(test)]
+pub fn init_test_cfg() {
+    CONFIG.get_or_init(|| Cli::parse_from(["dockerprom"]));
+}
+
 impl Cli {
     pub fn start() -> Cli {
         let mut out = Cli::parse();
@@ -119,10 +564,92 @@ impl Cli {
             exit(1);
         }
 
+        if out.tls_cert.is_some() != out.tls_key.is_some() {
+            eprintln!("\x1b[1;31mERROR: --tls-cert and --tls-key have to be given together.\x1b[0m");
+            exit(1);
+        }
+
+        if out.basicauth.is_some() && out.bearer_token.is_some() {
+            eprintln!("\x1b[1;31mERROR: Cannot pass both --basicauth and --bearer-token.\x1b[0m");
+            exit(1);
+        }
+
+        if out.runtime == Runtime::Podman && (out.only_running || out.since_ms > 0) {
+            eprintln!("\x1b[1;31mERROR: --only-running and --since-ms need container run state, which podman doesn't have in its containers.json.\x1b[0m");
+            exit(1);
+        }
+
+        if out.selftest && out.dry_run {
+            eprintln!("\x1b[1;31mERROR: Cannot pass both --selftest and --dry-run.\x1b[0m");
+            exit(1);
+        }
+
+        if !out.metrics_path.starts_with('/') {
+            eprintln!("\x1b[1;31mERROR: --metrics-path has to start with a slash.\x1b[0m");
+            exit(1);
+        }
+
+        if !out.label_prefix.is_empty() && !crate::validate::is_label_name(&out.label_prefix) {
+            eprintln!("\x1b[1;31mERROR: --label-prefix {:?} isn't the start of a valid Prometheus label name.\x1b[0m", out.label_prefix);
+            exit(1);
+        }
+
+        out.aggregate_labels = out.aggregate_by.as_ref().map(|by| match by.as_str() {
+            "image" => ("image".to_owned(), None),
+            "compose_project" => ("compose_project".to_owned(), Some("com.docker.compose.project".to_owned())),
+            label_key => {
+                let sanitized: String = label_key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+                (format!("{}{sanitized}", out.label_prefix), Some(label_key.to_owned()))
+            }
+        });
+        if let Some((label, _)) = &out.aggregate_labels {
+            if !crate::validate::is_label_name(label) {
+                eprintln!("\x1b[1;31mERROR: --aggregate-by {:?} doesn't make a valid Prometheus label name.\x1b[0m", out.aggregate_by.as_deref().unwrap_or_default());
+                exit(1);
+            }
+            info!("Aggregating metrics by {label}.");
+        }
+
+        out.node_name = out.node_label.as_deref().map(|node| match node {
+            "auto" => lookup_hostname(out.node_label_strip_domain),
+            node => node.to_owned()
+        });
+
         out.exclude_labels_set = process_labels(&out.exclude_labels, "Excluding");
         out.include_labels_set = process_labels(&out.include_labels, "Including");
 
-        check_read_dir(&out.containers_dir, "containers");
+        out.exclude_container = out.exclude_container.iter()
+            .flat_map(|c| c.split(','))
+            .map(|c| c.trim().trim_start_matches('/').to_owned())
+            .filter(|c| !c.is_empty())
+            .collect();
+
+        out.label_selectors = process_labels(&out.label_selector, "Selecting containers by").into_iter()
+            .map(|selector| match selector.split_once('=') {
+                Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                None => (selector, None)
+            })
+            .collect();
+
+        out.name_regex_include_compiled = out.name_regex_include.as_deref().map(|r| compile_name_regex(r, "include"));
+        out.name_regex_exclude_compiled = out.name_regex_exclude.as_deref().map(|r| compile_name_regex(r, "exclude"));
+
+        if let Some(template) = &out.cgroup_path_template {
+            let name = template.rsplit('/').next().unwrap_or_default();
+            if template.matches("{id}").count() != 1 || !name.contains("{id}") {
+                eprintln!("\x1b[1;31mERROR: --cgroup-path-template needs exactly one {{id}}, in its last path component.\x1b[0m");
+                exit(1);
+            }
+            if name.contains('*') {
+                eprintln!("\x1b[1;31mERROR: --cgroup-path-template can only have * wildcards before its last path component.\x1b[0m");
+                exit(1);
+            }
+            info!("Looking for container cgroups at {template:?}.");
+        }
+
+        for containers_dir in &out.containers_dir {
+            check_read_dir(containers_dir, "containers");
+        }
         check_read_dir(&out.cgroupfs_dir, "cgroupfs");
 
         if out.min_metadata_refresh_ms > 0 {
@@ -133,6 +660,10 @@ impl Cli {
             info!("HTTP Basic auth will be required.");
             format!("Basic {}", BASE64_STANDARD.encode(s))
         });
+        out.bearer_token_header = out.bearer_token.as_ref().map(|token| {
+            info!("HTTP Bearer token auth will be required.");
+            format!("Bearer {token}")
+        });
 
         CONFIG.set(out.clone()).unwrap();
         out
@@ -174,4 +705,30 @@ fn check_read_dir(dir: &PathBuf, dir_name: &str) {
         eprintln!("If you're running this tool within a container, maybe check your volume mounts.");
         exit(e.raw_os_error().unwrap_or(1));
     }
-}
\ No newline at end of file
+}
+
+fn compile_name_regex(regex: &str, kind: &str) -> Regex {
+    match Regex::new(regex) {
+        Ok(compiled) => {
+            info!("{} containers whose name matches {regex:?}.", if kind == "exclude" { "Not exporting" } else { "Only exporting" });
+            compiled
+        }
+        Err(e) => {
+            eprintln!("\x1b[1;31mERROR: Invalid --name-regex-{kind} {regex:?}\x1b[0m");
+            eprintln!("\x1b[31mError details: {}\x1b[0m", e);
+            exit(1);
+        }
+    }
+}
+
+/// This host's hostname, or "unknown" if it can't be found out.
+fn lookup_hostname(strip_domain: bool) -> String {
+    let hostname = match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(hostname) if !hostname.trim().is_empty() => hostname.trim().to_owned(),
+        Ok(_) => { warn!("Hostname is empty, using \"unknown\" for the node label."); return "unknown".to_owned() }
+        Err(e) => { warn!("Couldn't look up hostname, using \"unknown\" for the node label: {e}"); return "unknown".to_owned() }
+    };
+    let hostname = if strip_domain { hostname.split('.').next().unwrap_or_default().to_owned() } else { hostname };
+    info!("Using node label {hostname:?}.");
+    hostname
+}
@@ -1,7 +1,9 @@
-use std::{collections::HashMap, fs::{self, File}, io::BufReader, path::PathBuf, sync::Mutex, time::{Duration, Instant}};
+use std::{collections::HashMap, fs::{self, File}, hash::{BuildHasher, Hasher, RandomState}, io::{self, BufReader}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, mpsc, Mutex}, thread, time::{Duration, Instant}};
 use anyhow::Result;
-use serde::Deserialize;
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
 use lazy_static::lazy_static;
+use notify::{RecursiveMode, Watcher};
 
 use crate::cli::cfg;
 
@@ -10,16 +12,98 @@ lazy_static! {
     static ref LAST_CONTAINER_REFRESH: Mutex<Instant> = Mutex::new(Instant::now() - Duration::from_secs(1000));
 }
 
+/// Which container engine's metadata is in --containers-dir, and whose cgroup naming to look for.
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum Runtime { Docker, Podman }
+
+/// Whether --watch-containers-dir is keeping the metadata up to date, so unknown IDs don't need a refresh.
+static WATCHING_CONTAINERS_DIR: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait for more changes after one comes in, before re-reading. Creating a container touches
+/// several files.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Whether the last metadata refresh could read --containers-dir.
+pub static METADATA_DIR_READABLE: AtomicBool = AtomicBool::new(true);
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ContainerDetails {
     #[serde(rename = "ID")]
     pub id: String,
     
-    #[serde(rename = "Name")]
+    /// Without the leading slash Docker keeps in config.v2.json
+    #[serde(rename = "Name", deserialize_with = "strip_leading_slash")]
     pub name: String,
     
     #[serde(rename = "Config")]
     pub config: ContainerConfig,
+
+    /// Docker actually keeps this in hostconfig.json next to config.v2.json, see [container_details_from_config_path]
+    #[serde(rename = "HostConfig", default)]
+    pub host_config: HostConfig,
+
+    #[serde(rename = "State", default)]
+    pub state: ContainerState,
+
+    /// How many times Docker has restarted the container because of its restart policy
+    #[serde(rename = "RestartCount", default)]
+    pub restart_count: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ContainerState {
+    #[serde(rename = "Running", default)]
+    pub running: bool,
+
+    /// Main process of the container, as seen from the host. 0 if it isn't running.
+    #[serde(rename = "Pid", default)]
+    pub pid: u32,
+
+    /// RFC 3339 timestamp in UTC, like 2024-06-01T12:00:00.123456789Z
+    #[serde(rename = "StartedAt", default)]
+    pub started_at: String,
+}
+
+impl ContainerState {
+    /// When the container was last started, in UNIX time. None if it never was or the timestamp can't be parsed.
+    pub fn started_at_unix(&self) -> Option<f64> {
+        parse_timestamp(&self.started_at)
+    }
+}
+
+/// Parses the RFC 3339 timestamps Docker writes into UNIX time. Docker never started containers are
+/// 0001-01-01T00:00:00Z, which comes out as None as well.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let (date, time) = timestamp.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, offset_sec) = match time.find(['Z', '+', '-']) {
+        Some(i) if &time[i..] == "Z" => (&time[..i], 0),
+        Some(i) => {
+            let (hours, minutes) = time[i + 1..].split_once(':')?;
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (&time[..i], if &time[i..i + 1] == "-" { -offset } else { offset })
+        }
+        None => return None
+    };
+    let mut time = time.splitn(3, ':');
+    let (hour, minute): (i64, i64) = (time.next()?.parse().ok()?, time.next()?.parse().ok()?);
+    let second: f64 = time.next()?.parse().ok()?;
+    let in_range = (1..=12).contains(&month) && (1..=31).contains(&day) && (0..24).contains(&hour)
+        && (0..60).contains(&minute) && (0.0..61.0).contains(&second);
+    if !in_range { return None }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar (Howard Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let unix = (days * 86400 + hour * 3600 + minute * 60 - offset_sec) as f64 + second;
+    if unix <= 0.0 { None } else { Some(unix) }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -27,18 +111,143 @@ pub struct ContainerConfig {
     #[serde(rename = "Image")]
     pub image: String,
 
-    #[serde(rename = "Labels")]
-    pub labels: HashMap<String, String>
+    /// Some runtimes write "Labels": null, or leave it out entirely
+    #[serde(rename = "Labels", default, deserialize_with = "null_as_default")]
+    pub labels: HashMap<String, String>,
+
+    #[serde(rename = "Entrypoint", default)]
+    pub entrypoint: Option<Vec<String>>,
+
+    #[serde(rename = "Cmd", default)]
+    pub cmd: Option<Vec<String>>,
+
+    /// user, uid, user:group or uid:gid; empty means whatever the image says, usually root
+    #[serde(rename = "User", default)]
+    pub user: String
+}
+
+impl ContainerConfig {
+    /// Whether the container runs as root, as far as its config says. An empty User means the image default, which
+    /// we can't see from here, so that's assumed to be root like it is for most images.
+    pub fn runs_as_root(&self) -> bool {
+        let user = self.user.split(':').next().unwrap_or_default();
+        user.is_empty() || user == "root" || user == "0"
+    }
+
+    /// The entrypoint and command together, as one space-separated string.
+    pub fn full_command(&self) -> String {
+        self.entrypoint.iter().chain(self.cmd.iter()).flatten()
+            .map(String::as_str).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct HostConfig {
+    #[serde(rename = "RestartPolicy", default)]
+    pub restart_policy: RestartPolicy,
+
+    /// bridge, host, none, container:<id>, or the name of a user-defined network
+    #[serde(rename = "NetworkMode", default)]
+    pub network_mode: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RestartPolicy {
+    #[serde(rename = "Name", default)]
+    pub name: String,
+}
+
+fn strip_leading_slash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Ok(name.strip_prefix('/').map(str::to_owned).unwrap_or(name))
+}
+
+fn null_as_default<'de, D: Deserializer<'de>, T: Default + Deserialize<'de>>(deserializer: D) -> Result<T, D::Error> {
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
 }
 
 #[inline]
 fn container_details_from_config_path(container_config: PathBuf) -> Result<ContainerDetails> {
-    let file = File::open(container_config)?;
+    let file = File::open(&container_config)?;
     let reader = BufReader::new(file);
-    Ok(serde_json::from_reader(reader)?)
+    let mut details: ContainerDetails = serde_json::from_reader(reader)?;
+
+    let host_config_path = container_config.with_file_name("hostconfig.json");
+    if host_config_path.exists() {
+        match File::open(&host_config_path).map_err(anyhow::Error::from)
+            .and_then(|f| Ok(serde_json::from_reader(BufReader::new(f))?)) {
+            Ok(host_config) => details.host_config = host_config,
+            Err(e) => debug!("Couldn't read {host_config_path:?}: {e}")
+        }
+    }
+    Ok(details)
+}
+
+/// One entry of podman's containers.json (really containers/storage's), which lists every container podman has.
+#[derive(Debug, Deserialize)]
+struct PodmanContainer {
+    id: String,
+
+    #[serde(default)]
+    names: Vec<String>,
+
+    /// ID of the image; its name is only in the metadata
+    #[serde(default)]
+    image: String,
+
+    /// More JSON, as a string
+    #[serde(default)]
+    metadata: String,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct PodmanMetadata {
+    #[serde(rename = "image-name", default)]
+    image_name: String,
+}
+
+impl From<PodmanContainer> for ContainerDetails {
+    /// There's no labels or run state in containers.json, podman keeps those in its own database.
+    fn from(cont: PodmanContainer) -> ContainerDetails {
+        let metadata: PodmanMetadata = serde_json::from_str(&cont.metadata).unwrap_or_default();
+        ContainerDetails {
+            name: cont.names.first().cloned().unwrap_or_default(),
+            config: ContainerConfig {
+                image: if metadata.image_name.is_empty() { cont.image } else { metadata.image_name },
+                labels: HashMap::new(),
+                entrypoint: None,
+                cmd: None,
+                user: String::new()
+            },
+            id: cont.id,
+            host_config: HostConfig::default(),
+            state: ContainerState::default(),
+            restart_count: 0
+        }
+    }
+}
+
+/// Reads podman's containers.json, which is right in --containers-dir (storage/overlay-containers) for podman.
+fn podman_containers(containers_dir: &Path) -> Result<Vec<ContainerDetails>> {
+    let file = File::open(containers_dir.join("containers.json"))?;
+    let containers: Vec<PodmanContainer> = serde_json::from_reader(BufReader::new(file))?;
+    Ok(containers.into_iter().map(ContainerDetails::from).collect())
+}
+
+/// Finds the container a full or short ID refers to, the way the docker CLI does: any unique prefix of a container
+/// ID works. Errors if the prefix is ambiguous, and returns None if no container matches.
+pub fn find_container_id(map: &HashMap<String, ContainerDetails>, id_prefix: &str) -> Result<Option<String>> {
+    if map.contains_key(id_prefix) { return Ok(Some(id_prefix.to_owned())) }
+    let mut matches = map.keys().filter(|id| id.starts_with(id_prefix));
+    match (matches.next(), matches.next()) {
+        (None, _) => Ok(None),
+        (Some(id), None) => Ok(Some(id.clone())),
+        (Some(_), Some(_)) => Err(anyhow::anyhow!("container ID prefix {id_prefix:?} matches more than one container"))
+    }
 }
 
 pub fn refresh_containers_map(map: &mut HashMap<String, ContainerDetails>) {
+    if WATCHING_CONTAINERS_DIR.load(Ordering::Relaxed) { return }
     if let Some(min_interval) = crate::cli::cfg().min_metadata_refresh {
         let now = Instant::now();
         let mut last = LAST_CONTAINER_REFRESH.lock().unwrap();
@@ -47,21 +256,205 @@ pub fn refresh_containers_map(map: &mut HashMap<String, ContainerDetails>) {
         }
         *last = now;
     }
+    reload_containers_map(map);
+}
+
+/// Re-reads all container metadata right away, ignoring --min-metadata-refresh-ms.
+fn reload_containers_map(map: &mut HashMap<String, ContainerDetails>) {
     debug!("Refreshing container metadata.");
+    if cfg().runtime == Runtime::Podman { return reload_podman_containers_map(map) }
 
-    if map.len() > 2000 {
-        info!("Container metadata map has grown too large, clearing it out.");
-        map.clear(); // crude anti-memory-leak mechanism i guess
+    let mut container_dirs = Vec::new();
+    let mut all_readable = true;
+    for containers_dir in &cfg().containers_dir {
+        if let Err(e) = find_container_dirs(containers_dir, cfg().container_config_depth, &mut container_dirs) {
+            // Keep going with whatever metadata we already have, rather than taking the whole exporter down
+            error!("Couldn't read containers directory {containers_dir:?}, keeping old container metadata: {e}");
+            all_readable = false;
+        }
     }
+    METADATA_DIR_READABLE.store(all_readable, Ordering::Relaxed);
+    if container_dirs.is_empty() && !all_readable { return }
 
-    let container_dirs = fs::read_dir(&cfg().containers_dir).expect("Couldn't read container directory.");
+    let config_filename = &cfg().container_config_filename;
     let mut count = 0;
-    for container_dir in container_dirs.filter_map(Result::ok) {
-        let container_config = container_dir.path().join("config.v2.json");
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+    for container_dir in container_dirs {
+        let container_config = container_dir.join(config_filename);
         match container_details_from_config_path(container_config) {
-            Ok(cont) => { count += 1; map.insert(cont.id.clone(), cont); }
-            Err(e) => { error!("Container config.v2.json parse error: {e}"); continue; }
+            Ok(cont) if cfg().only_running && !cont.state.running => { map.remove(&cont.id); }
+            Ok(cont) => {
+                if let Some(other_dir) = seen.insert(cont.id.clone(), container_dir.clone()) {
+                    warn!("Container {} is in both {other_dir:?} and {container_dir:?}, using the latter.", cont.id);
+                } else {
+                    count += 1;
+                }
+                map.insert(cont.id.clone(), cont);
+            }
+            Err(e) => { error!("Container {config_filename} parse error: {e}"); continue; }
         };
     }
+
+    // Removed containers' metadata sticks around otherwise. Only what's still on disk is kept, the rest can't be
+    // needed anymore; if the directory couldn't be read completely, we can't tell, so that waits for next time.
+    if map.len() > cfg().max_metadata_entries && all_readable {
+        let before = map.len();
+        map.retain(|id, _| seen.contains_key(id));
+        if map.len() < before {
+            info!("Container metadata map has grown too large, dropped {} removed containers.", before - map.len());
+        }
+    }
     info!("Refreshed container metadata, {count} containers present.")
-}
\ No newline at end of file
+}
+
+fn reload_podman_containers_map(map: &mut HashMap<String, ContainerDetails>) {
+    let mut containers: HashMap<String, ContainerDetails> = HashMap::new();
+    let mut all_readable = true;
+    for containers_dir in &cfg().containers_dir {
+        match podman_containers(containers_dir) {
+            Ok(found) => for cont in found {
+                if containers.contains_key(&cont.id) {
+                    warn!("Container {} is in more than one containers.json, using the one in {containers_dir:?}.", cont.id);
+                }
+                containers.insert(cont.id.clone(), cont);
+            },
+            Err(e) => {
+                error!("Couldn't read containers.json in {containers_dir:?}, keeping old container metadata: {e}");
+                all_readable = false;
+            }
+        }
+    }
+    METADATA_DIR_READABLE.store(all_readable, Ordering::Relaxed);
+
+    // containers.json always has every container, so there's nothing to keep from before, unless one couldn't be read
+    if all_readable { map.clear(); }
+    let count = containers.len();
+    map.extend(containers);
+    info!("Refreshed container metadata, {count} containers present.")
+}
+
+/// Collects every directory exactly `depth` levels below `dir`; these are where the config files should be.
+/// Only fails if `dir` itself can't be read; unreadable directories further down are just skipped.
+fn find_container_dirs(dir: &PathBuf, depth: u8, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    let subdirs = fs::read_dir(dir)?;
+    for subdir in subdirs.filter_map(Result::ok) {
+        if depth > 1 {
+            if subdir.file_type().is_ok_and(|t| t.is_dir()) {
+                if let Err(e) = find_container_dirs(&subdir.path(), depth - 1, out) {
+                    debug!("Couldn't read {:?}: {e}", subdir.path());
+                }
+            }
+        } else {
+            out.push(subdir.path());
+        }
+    }
+    Ok(())
+}
+
+pub fn spawn_background_refresh() {
+    let interval_ms = cfg().metadata_refresh_interval_ms;
+    if interval_ms == 0 { return }
+    let jitter_ms = cfg().refresh_jitter_ms;
+    info!("Refreshing container metadata in the background every {interval_ms} ms (+ up to {jitter_ms} ms jitter).");
+
+    thread::spawn(move || loop {
+        let jitter = if jitter_ms > 0 {
+            // RandomState is randomly seeded each time, which is plenty random for spreading out refreshes
+            RandomState::new().build_hasher().finish() % (jitter_ms as u64 + 1)
+        } else { 0 };
+        thread::sleep(Duration::from_millis(interval_ms as u64 + jitter));
+
+        let mut map = CONTAINERS_MAP.lock().unwrap();
+        reload_containers_map(&mut map);
+        *LAST_CONTAINER_REFRESH.lock().unwrap() = Instant::now();
+    });
+}
+
+/// Whether a file in --containers-dir is one we read metadata from.
+fn is_metadata_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| match cfg().runtime {
+        Runtime::Docker => name == cfg().container_config_filename.as_str() || name == "hostconfig.json",
+        Runtime::Podman => name == "containers.json"
+    })
+}
+
+pub fn spawn_containers_dir_watcher() {
+    if !cfg().watch_containers_dir { return }
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => { error!("Couldn't set up watching the containers directory, refreshing metadata as usual: {e}"); return }
+    };
+    // Docker's config files are in subdirectories, podman's containers.json right there
+    let mode = match cfg().runtime { Runtime::Docker => RecursiveMode::Recursive, Runtime::Podman => RecursiveMode::NonRecursive };
+    for containers_dir in &cfg().containers_dir {
+        if let Err(e) = watcher.watch(containers_dir, mode) {
+            error!("Couldn't watch {containers_dir:?}, refreshing metadata as usual: {e}");
+            return;
+        }
+        info!("Watching {containers_dir:?} for container metadata changes.");
+    }
+    WATCHING_CONTAINERS_DIR.store(true, Ordering::Relaxed);
+
+    thread::spawn(move || {
+        let _watcher = watcher; // stops watching when dropped
+        while let Ok(event) = rx.recv() {
+            match event {
+                // Container logs are in there too, and change all the time
+                Ok(event) if event.kind.is_access() || !event.paths.iter().any(|path| is_metadata_file(path)) => continue,
+                Ok(event) => trace!("Container metadata changed: {event:?}"),
+                Err(e) => {
+                    error!("Watching the containers directory failed, refreshing metadata as usual from now on: {e}");
+                    break;
+                }
+            }
+            thread::sleep(WATCH_DEBOUNCE);
+            while rx.try_recv().is_ok() {}
+
+            let mut map = CONTAINERS_MAP.lock().unwrap();
+            reload_containers_map(&mut map);
+            *LAST_CONTAINER_REFRESH.lock().unwrap() = Instant::now();
+        }
+        WATCHING_CONTAINERS_DIR.store(false, Ordering::Relaxed);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_labels_are_empty() {
+        let config = r#"{"ID": "abc", "Name": "/test", "Config": {"Image": "nginx", "Labels": null}}"#;
+        let details: ContainerDetails = serde_json::from_str(config).unwrap();
+        assert!(details.config.labels.is_empty());
+    }
+
+    fn assert_timestamp(timestamp: &str, expected: f64) {
+        let parsed = parse_timestamp(timestamp).unwrap_or_else(|| panic!("{timestamp} didn't parse"));
+        assert!((parsed - expected).abs() < 1e-6, "{timestamp} parsed as {parsed}, expected {expected}");
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_timestamp("2024-06-01T12:00:00Z", 1717243200.0);
+        assert_timestamp("2024-06-01T12:00:00.123456789Z", 1717243200.0 + 0.123456789);
+        assert_timestamp("1970-01-01T00:00:01.5Z", 1.5);
+        assert_timestamp("2024-06-01T14:00:00+02:00", 1717243200.0);
+        assert_timestamp("2024-06-01T06:30:00.25-05:30", 1717243200.25);
+        assert_timestamp("2024-02-29T00:00:00Z", 1709164800.0);
+        assert_timestamp("2000-03-01T00:00:00Z", 951868800.0);
+        assert_timestamp("2100-01-01T00:00:00Z", 4102444800.0);
+    }
+
+    #[test]
+    fn never_started_and_malformed_timestamps() {
+        // What Docker writes for containers that were never started
+        assert_eq!(parse_timestamp("0001-01-01T00:00:00Z"), None);
+        for malformed in ["", "yesterday", "2024-06-01", "2024-06-01T12:00:00", "2024-06-01T12:00Z", "2024-06-01 12:00:00Z",
+            "2024-06-01T12:00:00+0200", "2024-06-01T12:00:00ZZ", "2024-13-01T12:00:00Z", "2024-06-00T12:00:00Z",
+            "2024-06-01T25:00:00Z", "2024-06-01T12:00:inf", "2024-06-01T12:00:NaNZ", "2024-xx-01T12:00:00Z"] {
+            assert_eq!(parse_timestamp(malformed), None, "{malformed:?}");
+        }
+    }
+}
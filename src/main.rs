@@ -1,40 +1,227 @@
 mod containers;
 mod metrics;
 mod cli;
+mod validate;
+mod selector;
+mod openmetrics;
 
 use cli::{Cli, cfg};
-use containers::{refresh_containers_map, CONTAINERS_MAP};
-use metrics::{get_metrics_string, print_cgroup_detection_results};
-use hyper::body::Incoming;
+use containers::{find_container_id, refresh_containers_map, spawn_background_refresh, spawn_containers_dir_watcher, CONTAINERS_MAP};
+use metrics::{get_collector_metrics_string, get_debug_timings, get_metrics_string, is_collector, last_scrape_ms, print_cgroup_detection_results, record_scrape, redetect_cgroup_setup, record_serialized_scrape, scrape_error_count, scrape_found_nothing, selftest, set_selectors, wait_for_cgroups};
+use selector::{query_params, selectors_from_query};
+use validate::validate_metrics_output;
+use openmetrics::{accepts_openmetrics, to_openmetrics, OPENMETRICS_CONTENT_TYPE};
+use flate2::{write::GzEncoder, Compression};
+use http::HeaderValue;
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{crypto::ring, ServerConfig};
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
 use signal_hook::iterator::Signals;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 extern crate pretty_env_logger;
 #[macro_use] extern crate log;
 
-async fn service(req: Request<Incoming>) -> http::Result<Response<String>> {
+/// Content type of the Prometheus text exposition format, which is what we serve.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serves a request, gzipping the response if the client can take that. Large hosts make for large responses.
+async fn service(req: Request<Incoming>) -> http::Result<Response<Full<Bytes>>> {
+    let gzip = req.headers().get("Accept-Encoding").and_then(|h| h.to_str().ok()).is_some_and(accepts_gzip);
+    let (mut parts, body) = handle_request(req).await?.into_parts();
+    parts.headers.insert("Vary", HeaderValue::from_static("Accept-Encoding"));
+    if gzip && parts.status.is_success() {
+        let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 8), Compression::fast());
+        match encoder.write_all(body.as_bytes()).and_then(|_| encoder.finish()) {
+            Ok(compressed) => {
+                parts.headers.insert("Content-Encoding", HeaderValue::from_static("gzip"));
+                return Ok(Response::from_parts(parts, Full::new(Bytes::from(compressed))));
+            }
+            Err(e) => warn!("Couldn't gzip response, sending it uncompressed: {e}")
+        }
+    }
+    Ok(Response::from_parts(parts, Full::new(Bytes::from(body))))
+}
+
+/// Whether an Accept-Encoding header allows gzip, i.e. has gzip or * in it without q=0.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        matches!(params.next(), Some("gzip" | "*"))
+            && !params.any(|param| param.strip_prefix("q=").is_some_and(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0)))
+    })
+}
+
+async fn handle_request(req: Request<Incoming>) -> http::Result<Response<String>> {
     debug!("Got request for {}", req.uri());
 
-    if let Some(req_auth) = &cfg().basicauth_encoded {
+    // For load balancer health checks, which can't authenticate
+    if req.uri().path() == "/health" {
+        return match blocking(health).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Health check failed: {e}");
+                Response::builder()
+                    .status(500)
+                    .body("Error occured. Please see logs.".to_owned())
+            }
+        };
+    }
+
+    let required_auth = match (&cfg().basicauth_encoded, &cfg().bearer_token_header) {
+        (Some(basic), _) => Some((basic, "Basic")),
+        (None, Some(bearer)) => Some((bearer, "Bearer")),
+        (None, None) => None
+    };
+    if let Some((req_auth, scheme)) = required_auth {
         let auth_hdr = req.headers().get("Authorization");
-        if auth_hdr.is_none() || auth_hdr.unwrap() != req_auth {
-            debug!("Basicauth failed.");
-            if auth_hdr.is_none() { trace!("No Authorization header") }
-            else { trace!("Got wrong contents: {:?}", auth_hdr.unwrap()) }
+        if auth_hdr.is_none() || !constant_time_eq(auth_hdr.unwrap().as_bytes(), req_auth.as_bytes()) {
+            debug!("{scheme} auth failed.");
+            match auth_hdr {
+                None => trace!("No Authorization header"),
+                Some(hdr) => trace!("Got wrong contents: {:?}", hdr)
+            }
             
             return Response::builder()
                 .status(401)
-                .header("WWW-Authenticate", "Basic")
+                .header("WWW-Authenticate", scheme)
                 .body("".to_owned())
         }
     }
 
-    match get_metrics_string() {
-        Ok(output) => Response::builder().body(output),
+    if cfg().enable_debug_endpoints && req.uri().path() == "/debug/timings" {
+        return Response::builder().body(get_debug_timings());
+    }
+
+    // All metrics at / and --metrics-path, single families below --metrics-path
+    let path = req.uri().path();
+    let metrics_path = cfg().metrics_path.trim_end_matches('/');
+    let collector = match path.strip_prefix(metrics_path).and_then(|p| p.strip_prefix('/')) {
+        _ if path == "/" || path == metrics_path => None,
+        Some(collector) => Some(collector.to_owned()),
+        None => {
+            debug!("Nothing at {path}.");
+            return Response::builder()
+                .status(404)
+                .body("Not found.".to_owned())
+        }
+    };
+    // Before it counts as a scrape, so requests for families that don't exist don't keep the watchdog happy
+    if collector.as_deref().is_some_and(|collector| !is_collector(collector)) {
+        debug!("No such collector in {}.", req.uri());
+        return Response::builder()
+            .status(404)
+            .body("Unknown metric family.".to_owned())
+    }
+    let full_scrape = collector.is_none();
+
+    let selectors = match selectors_from_query(req.uri().query().unwrap_or("")) {
+        Ok(selectors) => selectors,
+        Err(e) => {
+            debug!("Bad match[] selector: {e}");
+            return Response::builder()
+                .status(400)
+                .body(format!("Bad match[] selector: {e}"))
+        }
+    };
+
+    // A short ID is resolved against the containers Docker knows about, so an ambiguous one is an error rather than
+    // a mix of containers. IDs Docker doesn't know (nested or Kubernetes containers) are used as they are.
+    let id = query_params(req.uri().query().unwrap_or(""), "id").next();
+    // A scrape refreshing the container metadata holds its lock while reading the disk, so wait for it elsewhere
+    let id = match id {
+        Some(id) => Some(blocking(move || find_container_id(&CONTAINERS_MAP.lock().unwrap(), &id).map(|full| full.unwrap_or(id)))
+            .await.and_then(|result| result)),
+        None => None
+    };
+    let id = match id {
+        Some(Ok(id)) => Some(id),
+        Some(Err(e)) => {
+            debug!("Bad id: {e}");
+            return Response::builder()
+                .status(400)
+                .body(format!("Bad id: {e}"))
+        }
+        None => None
+    };
+
+    record_scrape();
+    let key = req.uri().to_string();
+    let cache_key = key.clone();
+    let scrape = move || {
+        set_selectors(selectors);
+        match collector {
+            Some(collector) => get_collector_metrics_string(&collector, id.as_deref()),
+            None => Some(cached_metrics_string(cache_key, || get_metrics_string(id.as_deref())))
+        }
+    };
+    let scrape = async move {
+        if cfg().serialize_scrapes { serialized_scrape(key, scrape).await } else { run_blocking_scrape(scrape).await }
+    };
+    let metrics = match cfg().scrape_timeout_ms {
+        0 => scrape.await,
+        timeout_ms => match tokio::time::timeout(Duration::from_millis(timeout_ms), scrape).await {
+            Ok(metrics) => metrics,
+            Err(_) => {
+                // The scrape keeps going on its thread, there's no interrupting a stuck read
+                warn!("Scrape for {} took longer than {timeout_ms} ms, giving up on it.", req.uri());
+                record_scrape_outcome(false);
+                return Response::builder()
+                    .status(503)
+                    .body("Scrape timed out.".to_owned())
+            }
+        }
+    };
+
+    let Some(metrics) = metrics else {
+        debug!("No such collector in {}.", req.uri());
+        return Response::builder()
+            .status(404)
+            .body("Unknown metric family.".to_owned())
+    };
+
+    // Only a full scrape without match[] selectors or an id is expected to have every running container in it
+    let full_scrape = full_scrape && req.uri().query().is_none_or(|q| q.is_empty());
+    let checked = blocking(move || {
+        let found_nothing = matches!(&metrics, Ok(output) if full_scrape && scrape_found_nothing(output));
+        if found_nothing {
+            // Maybe we started before Docker and detected the cgroup setup wrong, the next scrape will tell
+            redetect_cgroup_setup();
+        }
+        let ok = metrics.is_ok() && !found_nothing;
+
+        let metrics = match metrics {
+            Ok(output) if cfg().validate_output => validate_metrics_output(&output)
+                .map(|_| output)
+                .map_err(|e| anyhow::anyhow!("metrics output failed validation, {e}")),
+            other => other
+        };
+        (metrics, ok)
+    }).await;
+    let (metrics, ok) = checked.unwrap_or_else(|e| (Err(e), false));
+    record_scrape_outcome(ok);
+
+    let openmetrics = req.headers().get("Accept").and_then(|accept| accept.to_str().ok()).is_some_and(accepts_openmetrics);
+    match metrics {
+        Ok(output) if openmetrics => Response::builder()
+            .header("Content-Type", OPENMETRICS_CONTENT_TYPE)
+            .body(to_openmetrics(&output)),
+        Ok(output) => Response::builder()
+            .header("Content-Type", PROMETHEUS_CONTENT_TYPE)
+            .body(output),
         Err(err) => {
             error!("Failed getting metrics: {err}");
             Response::builder()
@@ -44,48 +231,315 @@ async fn service(req: Request<Incoming>) -> http::Result<Response<String>> {
     }
 }
 
-fn register_terminate_signal() {
+/// Does one full scrape and prints it for --dry-run, returning whether it worked, including for every container.
+fn dry_run() -> bool {
+    print_cgroup_detection_results();
+    match get_metrics_string(None) {
+        Ok(output) => {
+            print!("{output}");
+            let errors = scrape_error_count();
+            if errors > 0 {
+                error!("Dry run failed with {errors} errors reading container metrics, see the warnings above (or -v for details).");
+            }
+            errors == 0
+        }
+        Err(e) => {
+            error!("Dry run failed, couldn't get metrics: {e}");
+            false
+        }
+    }
+}
+
+/// Compares credentials in time that only depends on their length, so they can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 200 if we can still read the cgroupfs and every containers directory, 503 otherwise.
+fn health() -> http::Result<Response<String>> {
+    let dirs = std::iter::once((&cfg().cgroupfs_dir, "cgroupfs")).chain(cfg().containers_dir.iter().map(|dir| (dir, "containers")));
+    for (dir, what) in dirs {
+        if let Err(e) = std::fs::read_dir(dir) {
+            warn!("Health check failed, can't read {what} directory {dir:?}: {e}");
+            return Response::builder()
+                .status(503)
+                .body(format!("can't read {what} directory"))
+        }
+    }
+    Response::builder().body("ok".to_owned())
+}
+
+/// Result of the last scrape run with --serialize-scrapes, for the requests that were waiting on it.
+struct SharedScrape {
+    /// Request URI, since only identical requests can share a result
+    key: String,
+    generation: u64,
+    result: Option<Result<String, String>>
+}
+
+static SCRAPE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+static SCRAPE_GENERATION: AtomicU64 = AtomicU64::new(0);
+static LAST_SCRAPE: Mutex<Option<SharedScrape>> = Mutex::new(None);
+
+/// Runs `scrape` with no other scrape running at the same time. Requests that were waiting while an identical one
+/// was being scraped get that one's result, instead of reading everything again right after it.
+async fn serialized_scrape(key: String, scrape: impl FnOnce() -> ScrapeResult + Send + 'static) -> ScrapeResult {
+    let arrived_at = SCRAPE_GENERATION.load(Ordering::Acquire);
+    let _lock = SCRAPE_LOCK.lock().await;
+
+    if let Some(last) = &*LAST_SCRAPE.lock().unwrap() {
+        if last.key == key && last.generation > arrived_at {
+            trace!("Reusing the scrape that finished while this request was waiting.");
+            record_serialized_scrape(true);
+            return last.result.clone().map(|r| r.map_err(anyhow::Error::msg));
+        }
+    }
+
+    record_serialized_scrape(false);
+    let result = run_blocking_scrape(scrape).await;
+    let generation = SCRAPE_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    let shared = result.as_ref().map(|r| match r { Ok(output) => Ok(output.clone()), Err(e) => Err(e.to_string()) });
+    *LAST_SCRAPE.lock().unwrap() = Some(SharedScrape { key, generation, result: shared });
+    result
+}
+
+/// Metrics from a recent scrape, for --cache-ttl-ms.
+struct CachedMetrics {
+    /// Request URI, since match[] selectors and ?id= change the output
+    key: String,
+    at: Instant,
+    output: String
+}
+
+static METRICS_CACHE: Mutex<Option<CachedMetrics>> = Mutex::new(None);
+
+/// Returns the metrics cached for `key` if they're younger than --cache-ttl-ms, otherwise runs `scrape` and caches
+/// its output if it succeeded. The cache isn't locked while scraping, so a stuck scrape doesn't block cache hits.
+fn cached_metrics_string(key: String, scrape: impl FnOnce() -> anyhow::Result<String>) -> anyhow::Result<String> {
+    let ttl = Duration::from_millis(cfg().cache_ttl_ms);
+    if ttl.is_zero() { return scrape() }
+
+    if let Some(cached) = &*METRICS_CACHE.lock().unwrap() {
+        if cached.key == key && cached.at.elapsed() < ttl {
+            trace!("Serving metrics cached {} ms ago.", cached.at.elapsed().as_millis());
+            return Ok(cached.output.clone());
+        }
+    }
+
+    let at = Instant::now();
+    let output = scrape()?;
+    *METRICS_CACHE.lock().unwrap() = Some(CachedMetrics { key, at, output: output.clone() });
+    Ok(output)
+}
+
+/// What a scrape closure returns: None for an unknown collector, otherwise the metrics or what went wrong.
+type ScrapeResult = Option<anyhow::Result<String>>;
+
+/// Runs a scrape on the blocking thread pool, see [blocking].
+async fn run_blocking_scrape(scrape: impl FnOnce() -> ScrapeResult + Send + 'static) -> ScrapeResult {
+    blocking(scrape).await.unwrap_or_else(|e| Some(Err(e)))
+}
+
+/// Runs synchronous work on the blocking thread pool. Anything that reads files (which can stall on a busy host) or
+/// waits on a lock a scrape might be holding would otherwise hold up every other connection, and keep
+/// --scrape-timeout-ms from firing.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| anyhow::anyhow!("blocking task failed: {e}"))
+}
+
+static CONSECUTIVE_SCRAPE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps track of failed scrapes in a row, and exits once there's been --max-consecutive-scrape-failures of them.
+fn record_scrape_outcome(ok: bool) {
+    let max_failures = cfg().max_consecutive_scrape_failures;
+    if max_failures == 0 { return }
+    if ok {
+        CONSECUTIVE_SCRAPE_FAILURES.store(0, Ordering::Relaxed);
+        return;
+    }
+    let failures = CONSECUTIVE_SCRAPE_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    if failures >= max_failures {
+        error!("CRITICAL: {failures} scrapes in a row failed, terminating so we can be restarted.");
+        std::process::exit(1);
+    }
+    warn!("Scrape failed ({failures} in a row, exiting at {max_failures}).");
+}
+
+/// How long in-flight requests get to finish after SIGTERM or SIGINT.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SIGTERM and SIGINT start a graceful shutdown through `shutdown`. Any other terminating signal, or a second one
+/// while shutting down, exits right away.
+fn register_terminate_signal(shutdown: watch::Sender<bool>) {
     let mut signals = Signals::new(signal_hook::consts::TERM_SIGNALS).unwrap();
     std::thread::spawn(move || {
-        let sig = signals.forever().next().unwrap();
-        eprintln!();
-        error!("Received signal {}, terminating.", match sig {
-            15 => "SIGTERM", 3 => "SIGQUIT", 2 => "SIGINT", _ => "?"
-        });
-        std::process::exit(1);
+        for sig in signals.forever() {
+            eprintln!();
+            let name = match sig { 15 => "SIGTERM", 3 => "SIGQUIT", 2 => "SIGINT", _ => "?" };
+            if matches!(sig, 15 | 2) && !*shutdown.borrow() {
+                info!("Received signal {name}, shutting down.");
+                shutdown.send_replace(true);
+                continue;
+            }
+            error!("Received signal {name}, terminating.");
+            std::process::exit(1);
+        }
     });
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
+fn spawn_watchdog() {
+    let timeout_ms = cfg().watchdog_timeout_ms;
+    if timeout_ms == 0 { return }
+    info!("Watchdog enabled, expecting a scrape at least every {timeout_ms} ms.");
+
+    let started_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    std::thread::spawn(move || {
+        let mut stalled = false;
+        loop {
+            std::thread::sleep(Duration::from_millis((timeout_ms / 4).max(100)));
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            let since_ms = now_ms.saturating_sub(last_scrape_ms().max(started_ms));
+            if since_ms <= timeout_ms {
+                stalled = false;
+                continue;
+            }
+            if cfg().watchdog_exit {
+                error!("CRITICAL: No scrape served in the last {since_ms} ms, terminating so we can be restarted.");
+                std::process::exit(1);
+            }
+            if !stalled {
+                error!("CRITICAL: No scrape served in the last {since_ms} ms. Is the exporter stuck, or is nothing scraping it?");
+                stalled = true;
+            }
+        }
+    });
+}
+
+fn main() -> anyhow::Result<()> {
     let cli = Cli::start();
     info!("Starting Docker container metrics Prometheus exporter.");
     debug!("Debug logging is enabled.");
     trace!("Trace logging is enabled.");
 
+    // Blocking wait, so do it before the runtime is up
+    if cli.startup_wait_ms > 0 {
+        info!("Waiting up to {} ms for container cgroups to show up.", cli.startup_wait_ms);
+        wait_for_cgroups(cli.startup_wait_ms);
+    }
+
+    let mut runtime = if cli.worker_threads == 0 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        info!("Serving requests on {} worker threads.", cli.worker_threads);
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(cli.worker_threads);
+        builder
+    };
+    let runtime = runtime.enable_all().build()?;
+    let result = runtime.block_on(run(cli));
+    // Don't wait for scrapes that timed out, they might be stuck on a read forever
+    runtime.shutdown_background();
+    result
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
     {
         let mut cont_map = CONTAINERS_MAP.lock().unwrap();
         refresh_containers_map(&mut cont_map);
     }
+    if cli.selftest {
+        std::process::exit(if selftest() { 0 } else { 1 });
+    }
+    if cli.dry_run {
+        std::process::exit(if dry_run() { 0 } else { 1 });
+    }
+    spawn_background_refresh();
+    spawn_containers_dir_watcher();
 
     print_cgroup_detection_results();
-    register_terminate_signal();
+    let (shutdown_tx, mut shutdown) = watch::channel(false);
+    register_terminate_signal(shutdown_tx);
+    spawn_watchdog();
+
+    let tls_acceptor = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(tls_acceptor(cert, key)?),
+        _ => None
+    };
 
     let listener = TcpListener::bind(cli.listen_addr).await?;
     info!("Listening on {}...", listener.local_addr()?);
 
+    let connection_limit = (cli.max_connections > 0).then(|| Arc::new(Semaphore::new(cli.max_connections)));
+    let mut connections = JoinSet::new();
     loop {
-        let (stream, _) = listener.accept().await?;
-        debug!("New connection from {:?}", stream.peer_addr().unwrap());
-        let io = TokioIo::new(stream);
-
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(service))
-                .await
-            {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Reap finished connections as we go
+            Some(_) = connections.join_next() => continue,
+            Ok(_) = shutdown.wait_for(|stop| *stop) => break
+        };
+        debug!("New connection from {peer:?}");
+        // Held until the connection is closed
+        let permit = match connection_limit.as_ref().map(|limit| limit.clone().try_acquire_owned()) {
+            Some(Err(_)) => {
+                warn!("Already {} connections open, closing the new one from {peer:?}.", cli.max_connections);
+                continue;
+            }
+            Some(Ok(permit)) => Some(permit),
+            None => None
+        };
+        let tls_acceptor = tls_acceptor.clone();
+        let shutdown = shutdown.clone();
+
+        connections.spawn(async move {
+            let _permit = permit;
+            let result = match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, shutdown).await,
+                    Err(err) => { debug!("TLS handshake failed: {err}"); return }
+                },
+                None => serve_connection(stream, shutdown).await
+            };
+            if let Err(err) = result {
                 error!("Error serving connection: {:?}", err);
             }
         });
     }
+
+    debug!("Stopped accepting connections, waiting for {} open ones.", connections.len());
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async { while connections.join_next().await.is_some() {} }).await;
+    if drained.is_err() {
+        warn!("{} connections still open after {} ms, closing them.", connections.len(), SHUTDOWN_TIMEOUT.as_millis());
+    }
+    info!("Shut down.");
+    Ok(())
+}
+
+/// Serves HTTP on a connection until the client closes it, or until shutdown, after finishing the request in
+/// progress if there is one.
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, mut shutdown: watch::Receiver<bool>) -> hyper::Result<()> {
+    let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(service));
+    tokio::pin!(conn);
+    tokio::select! {
+        result = conn.as_mut() => return result,
+        Ok(_) = shutdown.wait_for(|stop| *stop) => conn.as_mut().graceful_shutdown()
+    }
+    conn.await
+}
+
+/// Loads the --tls-cert certificate chain and --tls-key private key, both PEM.
+fn tls_acceptor(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("couldn't read TLS certificates from {cert:?}: {e}"))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow::anyhow!("couldn't read TLS private key from {key:?}: {e}"))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    info!("TLS is enabled.");
+    Ok(TlsAcceptor::from(Arc::new(config)))
 }
\ No newline at end of file
@@ -1,10 +1,13 @@
-use std::{fs, path::PathBuf};
+use std::{cell::{Cell, RefCell}, collections::{BTreeMap, HashMap, HashSet}, fs, io, num::{ParseFloatError, ParseIntError}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use anyhow::{Error, Result};
 use clap::ValueEnum;
 use prometheus_exporter_base::{MetricType, PrometheusInstance, PrometheusMetric};
 use lazy_static::lazy_static;
 use crate::cli::cfg;
 
+use crate::containers::{find_container_id, ContainerDetails, Runtime, METADATA_DIR_READABLE};
+use crate::selector::Selector;
+use crate::validate::is_label_name;
 use crate::refresh_containers_map;
 
 #[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
@@ -13,38 +16,187 @@ pub enum CgroupVersion { V1, V2 }
 #[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
 pub enum DockerCgroupDriver { Cgroupfs, Systemd }
 
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum MemoryUnit { Bytes, Mib }
+
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum CpuUnit { Seconds, Ms }
+
+#[derive(Copy, Clone, Debug, ValueEnum, PartialEq)]
+pub enum KubepodsMode { Off, Pod, Container }
+
+static INVALID_CONTAINER_IDS: AtomicU64 = AtomicU64::new(0);
+/// Directories that looked like container cgroups by their name length in the last scan of the memory controller
+static CGROUP_DIRS: AtomicU64 = AtomicU64::new(0);
+static SCRAPES: AtomicU64 = AtomicU64::new(0);
+static LAST_SCRAPE_MS: AtomicU64 = AtomicU64::new(0);
+static SERIALIZED_SCRAPES_SHARED: AtomicU64 = AtomicU64::new(0);
+static SERIALIZED_SCRAPES_RUN: AtomicU64 = AtomicU64::new(0);
+static WARNED_NO_PERCPU: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
-    static ref CGROUP_VER: CgroupVersion = figure_out_cgroup_ver();
-    static ref DOCKER_CG_DRIVER: DockerCgroupDriver = figure_out_docker_driver();
+    static ref CGROUP_SETUP: RwLock<Arc<CgroupSetup>> = RwLock::new(Arc::new(CgroupSetup::detect()));
+}
 
-    static ref MEMORY_DIR: PathBuf = generate_cgroup_dir("memory");
-    static ref CPU_DIR: PathBuf = generate_cgroup_dir("cpu");
-    static ref BLKIO_DIR: PathBuf = generate_cgroup_dir("blkio");
+/// The cgroup version and Docker cgroup driver, and where that puts the container cgroups of each controller.
+/// Detected on first use, and again by [redetect_cgroup_setup] if it looks like that went wrong.
+struct CgroupSetup {
+    version: CgroupVersion,
+    driver: DockerCgroupDriver,
+    memory_dir: PathBuf,
+    cpu_dir: PathBuf,
+    /// On v1, CPU usage is in the cpuacct controller, which may or may not be mounted together with cpu
+    cpuacct_dir: PathBuf,
+    blkio_dir: PathBuf,
+    freezer_dir: PathBuf,
+    cpuset_dir: PathBuf,
+    pids_dir: PathBuf,
+    expected_dir_name_len: usize
+}
 
-    static ref EXPECTED_DIR_NAME_LEN: usize = match *DOCKER_CG_DRIVER {
-        DockerCgroupDriver::Cgroupfs => 64,
-        DockerCgroupDriver::Systemd => 64 + 13 // docker-{64 chars}.scope
-    };
+impl CgroupSetup {
+    fn detect() -> CgroupSetup {
+        let version = figure_out_cgroup_ver();
+        let driver = figure_out_docker_driver(version);
+        let dir = |resource| generate_cgroup_dir(resource, version, driver);
+        CgroupSetup {
+            version,
+            driver,
+            memory_dir: dir("memory"),
+            cpu_dir: dir("cpu"),
+            cpuacct_dir: dir("cpuacct"),
+            blkio_dir: dir("blkio"),
+            freezer_dir: dir("freezer"),
+            cpuset_dir: dir("cpuset"),
+            pids_dir: dir("pids"),
+            expected_dir_name_len: match (cgroup_path_template(), driver) {
+                (Some((_, prefix, suffix)), _) => prefix.len() + 64 + suffix.len(),
+                (None, DockerCgroupDriver::Cgroupfs) if cfg().runtime == Runtime::Podman => 64 + 7, // libpod-{64 chars}
+                (None, DockerCgroupDriver::Cgroupfs) => 64,
+                (None, DockerCgroupDriver::Systemd) => 64 + 13 // docker-{64 chars}.scope or libpod-{64 chars}.scope
+            }
+        }
+    }
+
+    /// The controller directory a collector reads the container cgroups from, for the ones that read the cgroupfs.
+    fn collector_dir(&self, collector: &str) -> Option<&Path> {
+        match collector {
+            "memory" | "info" | "network" => Some(&self.memory_dir),
+            "cpu" if self.version == CgroupVersion::V1 => Some(&self.cpuacct_dir),
+            "cpu" | "sched" => Some(&self.cpu_dir),
+            "pids" => Some(&self.pids_dir),
+            "blkio" => Some(&self.blkio_dir),
+            "freezer" => Some(&self.freezer_dir),
+            "cpuset" => Some(&self.cpuset_dir),
+            _ => None
+        }
+    }
+
+    /// Top of the controller `controller_dir` is in, like cgroupfs/memory on v1 or the cgroupfs itself on v2.
+    fn controller_root(&self, controller_dir: &Path) -> PathBuf {
+        match controller_dir.strip_prefix(&cfg().cgroupfs_dir).ok().and_then(|rel| rel.components().next()) {
+            Some(controller) if self.version == CgroupVersion::V1 => cfg().cgroupfs_dir.join(controller),
+            _ => cfg().cgroupfs_dir.clone()
+        }
+    }
+
+    /// Why a collector has nothing to read, if its controller is missing. On v1 each controller is mounted
+    /// separately, and some (like blkio or pids) aren't always. On v2 they're all in one hierarchy, but each one has
+    /// to be enabled for the container cgroups' parent, or their files aren't there.
+    fn missing_controller(&self, collector: &str) -> Option<String> {
+        let dir = self.collector_dir(collector)?;
+        match self.version {
+            CgroupVersion::V1 => {
+                let root = self.controller_root(dir);
+                (!root.is_dir()).then(|| format!("Cgroup controller directory {root:?} doesn't exist"))
+            }
+            CgroupVersion::V2 => {
+                // The rest only need files every v2 cgroup has, like cpu.stat and cgroup.procs
+                let controller = match collector { "memory" => "memory", "pids" => "pids", "blkio" => "io", "cpuset" => "cpuset", _ => return None };
+                // What the containers get is in their parent's subtree_control. Before Docker has made that parent,
+                // go by what's available at all.
+                let (file, enabled) = [dir.join("cgroup.subtree_control"), cfg().cgroupfs_dir.join("cgroup.controllers")].into_iter()
+                    .find_map(|file| fs::read_to_string(&file).ok().map(|enabled| (file, enabled)))?;
+                (!enabled.split_ascii_whitespace().any(|c| c == controller))
+                    .then(|| format!("Cgroup controller {controller} isn't enabled in {file:?}"))
+            }
+        }
+    }
 }
 
-fn generate_cgroup_dir(resource: &str) -> PathBuf {
+fn cgroups() -> Arc<CgroupSetup> {
+    CGROUP_SETUP.read().unwrap().clone()
+}
+
+/// Detects the cgroup version and driver again, for when a scrape found no containers even though Docker has some
+/// running. If the exporter started before Docker or the cgroup controllers were set up, the first detection can
+/// have gotten it wrong, and this fixes that without a restart.
+pub fn redetect_cgroup_setup() {
+    let old = cgroups();
+    let new = CgroupSetup::detect();
+    if (new.version, new.driver) == (old.version, old.driver) {
+        debug!("Found no containers, but re-detecting the cgroup setup gave the same result.");
+        return;
+    }
+    warn!("Found no containers with cgroup version {:?} and Docker cgroup driver {:?}, switching to the newly detected \
+        cgroup version {:?} and Docker cgroup driver {:?}.", old.version, old.driver, new.version, new.driver);
+    *CGROUP_SETUP.write().unwrap() = Arc::new(new);
+}
+
+fn generate_cgroup_dir(resource: &str, version: CgroupVersion, driver: DockerCgroupDriver) -> PathBuf {
     let mut out = cfg().cgroupfs_dir.clone();
-    match (*CGROUP_VER, *DOCKER_CG_DRIVER) {
-        (CgroupVersion::V1, DockerCgroupDriver::Cgroupfs) => out.push(format!("{resource}/docker")),
-        (CgroupVersion::V1, DockerCgroupDriver::Systemd) => out.push(format!("{resource}/system.slice")),
-        (CgroupVersion::V2, DockerCgroupDriver::Cgroupfs) => out.push("docker"),
-        (CgroupVersion::V2, DockerCgroupDriver::Systemd) => out.push("system.slice")
+    let resource = &*if version == CgroupVersion::V1 { v1_controller_dir_name(resource) } else { resource.to_owned() };
+    if let Some((parent, _, _)) = cgroup_path_template() {
+        if version == CgroupVersion::V1 { out.push(resource); }
+        out.push(parent.trim_start_matches('/'));
+        return out;
     }
+    if version == CgroupVersion::V1 { out.push(resource); }
+    out.push(runtime_cgroup_parent(driver));
     out
 }
 
-fn figure_out_docker_driver() -> DockerCgroupDriver {
+/// Name of the directory a v1 controller is mounted at. Usually that's just the controller name (co-mounted ones
+/// like cpu,cpuacct get symlinks), but not every distro adds those symlinks, so look for a co-mount too.
+fn v1_controller_dir_name(controller: &str) -> String {
+    if cfg().cgroupfs_dir.join(controller).exists() { return controller.to_owned() }
+    let comounted = fs::read_dir(&cfg().cgroupfs_dir).into_iter().flatten().filter_map(Result::ok)
+        .map(|dir| dir.file_name().to_string_lossy().into_owned())
+        .find(|name| name.split(',').any(|c| c == controller));
+    match comounted {
+        Some(name) => { debug!("Using co-mounted {name} for the {controller} controller."); name }
+        None => controller.to_owned()
+    }
+}
+
+/// Splits --cgroup-path-template into the directory the container cgroups are in, and what comes before and after
+/// the ID in their names. The template has already been checked in [Cli::start](crate::cli::Cli::start).
+fn cgroup_path_template() -> Option<(&'static str, &'static str, &'static str)> {
+    let template = cfg().cgroup_path_template.as_deref()?;
+    let (parent, name) = template.rsplit_once('/').unwrap_or(("", template));
+    let (prefix, suffix) = name.split_once("{id}")?;
+    Some((parent, prefix, suffix))
+}
+
+/// The directory the runtime puts its containers' cgroups in with this cgroup driver, relative to the cgroupfs (or
+/// the controller's directory on v1). Podman's containers are in libpod_parent/libpod-<id> or
+/// machine.slice/libpod-<id>.scope.
+fn runtime_cgroup_parent(driver: DockerCgroupDriver) -> &'static str {
+    match (cfg().runtime, driver) {
+        (Runtime::Docker, DockerCgroupDriver::Cgroupfs) => "docker",
+        (Runtime::Docker, DockerCgroupDriver::Systemd) => "system.slice",
+        (Runtime::Podman, DockerCgroupDriver::Cgroupfs) => "libpod_parent",
+        (Runtime::Podman, DockerCgroupDriver::Systemd) => "machine.slice"
+    }
+}
+
+fn figure_out_docker_driver(cgver: CgroupVersion) -> DockerCgroupDriver {
     let cli = cfg();
-    let cgver = *CGROUP_VER;
     let mut dir = cli.cgroupfs_dir.clone();
     if cgver == CgroupVersion::V1 { dir.push("memory"); }
-    let mut ls = std::fs::read_dir(&dir).unwrap_or_else(|_| panic!("Failed to read {:?} directory.", &dir));
-    let guess = if ls.any(|entry| entry.unwrap().file_name() == "docker") {
+    let ls = std::fs::read_dir(&dir).unwrap_or_else(|_| panic!("Failed to read {:?} directory.", &dir));
+    let cgroupfs_parent = runtime_cgroup_parent(DockerCgroupDriver::Cgroupfs);
+    let guess = if readable_entries(ls).any(|entry| entry.file_name() == cgroupfs_parent) {
         DockerCgroupDriver::Cgroupfs
     } else {
         DockerCgroupDriver::Systemd
@@ -60,8 +212,8 @@ fn figure_out_docker_driver() -> DockerCgroupDriver {
 fn figure_out_cgroup_ver() -> CgroupVersion {
     let cli = crate::cli::cfg();
     let dir = &cli.cgroupfs_dir;
-    let mut ls = std::fs::read_dir(dir).unwrap_or_else(|_| panic!("Failed to read {:?} directory.", &dir));
-    let guess = if ls.any(|entry| entry.unwrap().file_name() == "memory") {
+    let ls = std::fs::read_dir(dir).unwrap_or_else(|_| panic!("Failed to read {:?} directory.", &dir));
+    let guess = if readable_entries(ls).any(|entry| entry.file_name() == "memory") {
         CgroupVersion::V1
     } else {
         CgroupVersion::V2
@@ -74,225 +226,2190 @@ fn figure_out_cgroup_ver() -> CgroupVersion {
     guess
 }
 
+/// Waits up to `timeout_ms` for Docker's container cgroups to show up, for --startup-wait-ms. The cgroup version
+/// and driver are only detected once, so detecting them before Docker (or the cgroup controllers) are set up on
+/// boot would get them wrong for as long as we run.
+pub fn wait_for_cgroups(timeout_ms: u64) {
+    let root = &cfg().cgroupfs_dir;
+    let template_parent = cgroup_path_template().map(|(parent, _, _)| parent.trim_start_matches('/'));
+    let candidates: Vec<PathBuf> = match template_parent {
+        Some(parent) => vec![root.join(parent), root.join("memory").join(parent)],
+        None => [DockerCgroupDriver::Cgroupfs, DockerCgroupDriver::Systemd].into_iter().map(runtime_cgroup_parent)
+            .flat_map(|parent| [root.join(parent), root.join("memory").join(parent)]).collect()
+    };
+    let has_container_cgroups = |dir: &PathBuf| fs::read_dir(dir).into_iter().flatten().filter_map(Result::ok)
+        .any(|entry| entry.file_type().is_ok_and(|t| t.is_dir()) && entry.file_name().len() >= 64);
+
+    let start = Instant::now();
+    while !candidates.iter().flat_map(|dir| expand_cgroup_dir(dir)).any(|dir| has_container_cgroups(&dir)) {
+        if start.elapsed() >= Duration::from_millis(timeout_ms) {
+            warn!("No container cgroups showed up in {root:?} within {timeout_ms} ms, detecting the cgroup setup anyway.");
+            return;
+        }
+        trace!("No container cgroups yet, waiting.");
+        thread::sleep(Duration::from_millis(500));
+    }
+    debug!("Found container cgroups after {} ms.", start.elapsed().as_millis());
+}
+
 pub fn print_cgroup_detection_results() {
-    info!("Assuming: cgroup version {:?}, Docker cgroup driver {:?}.",
-        *CGROUP_VER, *DOCKER_CG_DRIVER);
+    let setup = cgroups();
+    info!("Assuming: cgroup version {:?}, Docker cgroup driver {:?}.", setup.version, setup.driver);
+
+    let mut missing: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (name, _) in COLLECTORS {
+        if let Some(why) = setup.missing_controller(name) {
+            missing.entry(why).or_default().push(name);
+        }
+    }
+    for (why, collectors) in missing {
+        warn!("{why}, skipping the {} metrics.", collectors.join(", "));
+    }
 }
 
-fn dir_name_to_cont_id(dir_name: &str) -> &str {
-    match *DOCKER_CG_DRIVER {
-        DockerCgroupDriver::Cgroupfs => dir_name,
-        DockerCgroupDriver::Systemd => &dir_name[7..71]
-        // slice might panic, but we've already checked appropriate length
+/// Returns the container ID for a cgroup directory, or None (and counts it) if it doesn't look like one.
+fn dir_name_to_cont_id(dir_name: &str, driver: DockerCgroupDriver) -> Option<&str> {
+    let cont_id = match (cgroup_path_template(), driver) {
+        (Some((_, prefix, suffix)), _) => dir_name.strip_prefix(prefix).and_then(|n| n.strip_suffix(suffix)).unwrap_or_default(),
+        (None, DockerCgroupDriver::Cgroupfs) if cfg().runtime == Runtime::Podman => dir_name.strip_prefix("libpod-").unwrap_or_default(),
+        (None, DockerCgroupDriver::Cgroupfs) => dir_name,
+        // Other units in system.slice can have names just as long, those aren't worth a warning
+        (None, DockerCgroupDriver::Systemd) => parse_systemd_scope(dir_name)?
+    };
+    if !is_container_id(cont_id) {
+        // Every collector comes across the same directories on every scrape
+        if WARNED_INVALID_IDS.lock().unwrap().insert(dir_name.to_owned()) {
+            warn!("Cgroup directory {dir_name:?} doesn't contain a valid container ID, skipping it. Is the cgroup driver detected correctly?");
+        } else {
+            trace!("Skipping cgroup directory {dir_name:?} without a valid container ID.");
+        }
+        INVALID_CONTAINER_IDS.fetch_add(1, Ordering::Relaxed);
+        return None;
     }
+    Some(cont_id)
 }
 
-pub fn get_metrics_string() -> Result<String> {
-    let mut output = String::with_capacity(1024);
-    output += &get_memory_metric()?;
-    output += &get_cpu_metrics()?;
-    output += &get_blkio_metrics()?;
-    Ok(output)
+/// What might be the container ID in a cgroup directory name, with any of the runtimes' prefixes and suffixes
+/// (docker-<id>.scope, libpod-<id>.scope, libpod-<id>) taken off. For looking through cgroups we don't know the
+/// layout of, so the result still has to be checked with [is_container_id].
+fn cgroup_name_to_id(name: &str) -> &str {
+    parse_systemd_scope(name).or_else(|| name.strip_prefix("libpod-")).unwrap_or(name)
 }
 
-fn get_memory_metric() -> Result<String> {
-    let mut metric_rss = PrometheusMetric::build()
-        .with_name("container_memory_usage")
-        .with_metric_type(MetricType::Gauge)
-        .with_help("Memory used by the container, in bytes")
-        .build();
+/// Gets the container ID out of a systemd scope name like docker-<id>.scope, or podman's libpod-<id>.scope.
+fn parse_systemd_scope(name: &str) -> Option<&str> {
+    name.strip_prefix("docker-").or_else(|| name.strip_prefix("libpod-"))?.strip_suffix(".scope")
+}
+
+type Collector = fn(&CgroupSetup) -> Result<String>;
+
+/// How deep --blkio-recursive-sum goes when it has to walk a container's cgroup subtree by hand.
+const MAX_BLKIO_RECURSION_DEPTH: u8 = 8;
+
+/// Longest command that goes into the container_info command label (see --cmd-label) before it gets cut off.
+const MAX_COMMAND_LABEL_LEN: usize = 128;
+
+/// Most characters an f64 between 0 and a few hours takes when displayed, like 0.000012345678901234567.
+const MAX_F64_DISPLAY_LEN: usize = 24;
+
+/// Length of a short container ID, for --short-id.
+const SHORT_ID_LEN: usize = 12;
+
+/// Metric families that come from Docker's metadata alone rather than the cgroupfs.
+const METADATA_ONLY_FAMILIES: &[&str] = &["container_running", "container_start_time_seconds"];
+
+/// Every metrics collector, by the name used for it in the /metrics/<name> route.
+const COLLECTORS: &[(&str, Collector)] = &[
+    ("memory", get_memory_metric),
+    ("pids", get_pids_metric),
+    ("cpu", get_cpu_metrics),
+    ("blkio", get_blkio_metrics),
+    ("freezer", get_freezer_metric),
+    ("cpuset", get_cpuset_metrics),
+    ("info", get_info_metric),
+    ("state", get_state_metrics),
+    ("network", get_network_metrics),
+    ("sched", get_sched_metrics),
+];
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// A container's cgroup directory under one of the controller directories.
+struct ContainerCgroup {
+    id: String,
+    path: PathBuf,
+    /// For containers nested inside another container (see --nested-cgroup-depth), the outer container's ID.
+    parent_id: Option<String>,
+    /// For Kubernetes pods and their containers (see --kubepods), the pod's UID.
+    pod_uid: Option<String>
+}
 
-    let memory_dirs = fs::read_dir(&*MEMORY_DIR).unwrap_or_else(|_| panic!("Couldn't read memory directory {:?}", *MEMORY_DIR));
-    for memory_dir_sub in memory_dirs.filter_map(Result::ok) {
-        if !memory_dir_sub.file_type().unwrap().is_dir()
-            || memory_dir_sub.file_name().len() != *EXPECTED_DIR_NAME_LEN { continue }
+impl ContainerCgroup {
+    /// Whether this is one of Docker's own containers, whose metadata we can read from --containers-dir.
+    fn has_docker_metadata(&self) -> bool {
+        self.parent_id.is_none() && self.pod_uid.is_none()
+    }
+}
 
-        let memory_usage: u64 = fs::read_to_string(memory_dir_sub.path().join(match *CGROUP_VER {
-            CgroupVersion::V1 => "memory.usage_in_bytes",
-            CgroupVersion::V2 => "memory.current"
-        }))?.trim_end().parse()?;
+/// Lists the container cgroups in a controller directory, including nested ones if --nested-cgroup-depth is set.
+fn container_cgroups(setup: &CgroupSetup, controller_dir: &Path) -> Vec<ContainerCgroup> {
+    let mut out = Vec::new();
+    let expected_dir_name_len = setup.expected_dir_name_len;
+    let mut cgroup_dirs = 0;
+    if cfg().cgroup_recursive {
+        let root = setup.controller_root(controller_dir);
+        find_container_cgroups_recursive(&root, cfg().cgroup_max_depth, &mut out, &mut cgroup_dirs);
+    } else {
+        for parent in expand_cgroup_dir(controller_dir) {
+            // Not there before Docker has started a container, or when nothing uses this controller
+            let dirs = fs::read_dir(&parent)
+                .inspect_err(|e| debug!("Couldn't read cgroup directory {parent:?}: {e}"))
+                .into_iter().flatten();
+            for dir in readable_entries(dirs) {
+                DIRS_SCANNED.set(DIRS_SCANNED.get() + 1);
+                match dir.file_type() {
+                    Ok(file_type) if file_type.is_dir() => (),
+                    Ok(_) => continue,
+                    Err(e) => { debug!("Skipping {:?}, couldn't get its file type: {e}", dir.path()); continue }
+                }
+                if dir.file_name().len() != expected_dir_name_len { continue }
+                cgroup_dirs += 1;
 
-        let dir_name = memory_dir_sub.file_name().into_string();
-        if let Err(ref e) = dir_name { error!("Failed to read dirname {e:?}"); continue };
-        let dir_name = dir_name.unwrap();
-        let cont_id = dir_name_to_cont_id(&dir_name);
-        render_and_append_instance(&mut metric_rss, memory_usage, cont_id);
+                let dir_name = match dir.file_name().into_string() {
+                    Ok(dir_name) => dir_name,
+                    Err(e) => { error!("Failed to read dirname {e:?}"); continue }
+                };
+                let Some(cont_id) = dir_name_to_cont_id(&dir_name, setup.driver) else { continue };
+                add_container_cgroup(cont_id, dir.path(), &mut out);
+            }
+        }
+    }
+    if cfg().kubepods != KubepodsMode::Off {
+        // The kubepods hierarchy sits next to Docker's, at the top of the controller
+        if let Some(root) = controller_dir.parent() {
+            find_kubepods_cgroups(&root.join("kubepods"), 2, &mut out);
+            find_kubepods_cgroups(&root.join("kubepods.slice"), 2, &mut out);
+        }
+    }
+    if controller_dir == setup.memory_dir {
+        CGROUP_DIRS.store(cgroup_dirs, Ordering::Relaxed);
     }
+    ID_FILTER.with_borrow(|id_filter| if let Some(id) = id_filter {
+        // Containers nested in the requested one come along, like with the other filters
+        out.retain(|cgroup| cgroup.id.starts_with(id.as_str()) || cgroup.parent_id.as_ref().is_some_and(|p| p.starts_with(id.as_str())));
+    });
+    out
+}
 
-    Ok(metric_rss.render() + "\n")
+/// The directories matching `dir`, whose components can have * wildcards in them from --cgroup-path-template (like
+/// kubepods.slice/*/*). Without any, that's just `dir`, whether it exists or not.
+fn expand_cgroup_dir(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::new()];
+    for component in dir.components() {
+        let pattern = component.as_os_str().to_string_lossy();
+        if !pattern.contains('*') {
+            dirs.iter_mut().for_each(|dir| dir.push(component));
+            continue;
+        }
+        dirs = dirs.iter()
+            .flat_map(|dir| readable_entries(fs::read_dir(dir).into_iter().flatten()))
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()) && wildcard_match(&pattern, &entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path())
+            .collect();
+    }
+    dirs
 }
 
-fn get_cpu_metrics() -> Result<String> {
-    let mut metric_user = PrometheusMetric::build()
-        .with_name("container_cpu_user_total")
-        .with_metric_type(MetricType::Counter)
-        .with_help("CPU seconds used by the container in userspace")
-        .build();
+/// Whether `name` matches `pattern`, where each * stands for any number of characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (first, rest) = pattern.split_once('*').unwrap_or((pattern, ""));
+    let Some(mut name) = name.strip_prefix(first) else { return false };
+    if !pattern.contains('*') { return name.is_empty() }
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match name.find(part) {
+            Some(i) => name = &name[i + part.len()..],
+            None => return false
+        }
+    }
+    name.len() >= last.len() && name.ends_with(last)
+}
 
-    let mut metric_sys = PrometheusMetric::build()
-        .with_name("container_cpu_system_total")
-        .with_metric_type(MetricType::Counter)
-        .with_help("CPU seconds used by the container in kernelspace")
-        .build();
+/// The entries of a directory listing that could be read. Ones that couldn't (permission quirks, or the directory
+/// changing under us) are skipped, with a debug log.
+fn readable_entries<I: Iterator<Item = io::Result<fs::DirEntry>>>(entries: I) -> impl Iterator<Item = fs::DirEntry> {
+    entries.filter_map(|entry| entry.map_err(|e| debug!("Skipping unreadable directory entry: {e}")).ok())
+}
 
-    let cpu_dirs = fs::read_dir(&*CPU_DIR).unwrap_or_else(|_| panic!("Couldn't read CPU directory {:?}", *CPU_DIR));
-    for cpu_dir_sub in cpu_dirs.filter_map(Result::ok) {
-        if !cpu_dir_sub.file_type().unwrap().is_dir()
-            || cpu_dir_sub.file_name().len() != *EXPECTED_DIR_NAME_LEN { continue }
+/// Adds a top-level container's cgroup, and any nested in it, unless it's filtered out.
+fn add_container_cgroup(cont_id: &str, path: PathBuf, out: &mut Vec<ContainerCgroup>) {
+    if !should_include_container(cont_id) { trace!("Skipping filtered out container {cont_id}."); return }
+    let cgroup = ContainerCgroup { id: cont_id.to_owned(), path, parent_id: None, pod_uid: None };
+    if cfg().nested_cgroup_depth > 0 {
+        find_nested_cgroups(&cgroup.path, cont_id, cfg().nested_cgroup_depth, out);
+    }
+    out.push(cgroup);
+}
 
-        fn get_metrics(dir: PathBuf) -> Result<(f64, f64, String)> {
-            let dir_name = dir.file_name().unwrap().to_owned().into_string()
-                .map_err(|x| Error::msg(format!("Failed to read dirname {:?}", x)))?;
-            if *CGROUP_VER == CgroupVersion::V1 {
-                let usage_user_ns: f64 = fs::read_to_string(dir.join("cpuacct.usage_user"))?.trim_end().parse()?;
-                let usage_sys_ns:  f64 = fs::read_to_string(dir.join("cpuacct.usage_sys" ))?.trim_end().parse()?;
-                Ok((usage_user_ns / 1_000_000_000.0, usage_sys_ns / 1_000_000_000.0, dir_name))
-            } else {
-                let cpu_stat_file = dir.join("cpu.stat");
-                let cpu_stat = fs::read_to_string(&cpu_stat_file)?;
-                let mut user_us: Option<f64> = None;
-                let mut sys_us: Option<f64> = None;
-                for line in cpu_stat.lines() {
-                    if line.starts_with("user_usec") {
-                        user_us = Some(line.split_ascii_whitespace().last()
-                            .ok_or(Error::msg("Couldn't split user_usec line in cpu.stat"))?.parse()?);
-                    } else if line.starts_with("system_usec") {
-                        sys_us = Some(line.split_ascii_whitespace().last()
-                            .ok_or(Error::msg("Couldn't split system_usec line in cpu.stat"))?.parse()?);
-                    }
-                }
-                if let (Some(user_us), Some(sys_us)) = (user_us, sys_us) {
-                    Ok((user_us / 1_000_000.0, sys_us / 1_000_000.0, dir_name))
-                } else {
-                    Err(anyhow::anyhow!("Couldn't find one of user_usec or system_usec in {cpu_stat_file:?}"))
-                }
+/// For --cgroup-recursive, looks down to `depth` levels below `dir` for anything named like a container's cgroup
+/// (<id> or docker-<id>.scope), wherever it is. Doesn't look inside containers, that's --nested-cgroup-depth.
+fn find_container_cgroups_recursive(dir: &Path, depth: u8, out: &mut Vec<ContainerCgroup>, cgroup_dirs: &mut u64) {
+    // Directories can disappear at any moment here, so errors just mean there's nothing to find
+    let Ok(subdirs) = fs::read_dir(dir) else { return };
+    for subdir in subdirs.filter_map(Result::ok) {
+        DIRS_SCANNED.set(DIRS_SCANNED.get() + 1);
+        if !subdir.file_type().is_ok_and(|t| t.is_dir()) { continue }
+        let dir_name = subdir.file_name();
+        let dir_name = dir_name.to_string_lossy();
+        // --kubepods looks through these itself, and would find the same containers again
+        if cfg().kubepods != KubepodsMode::Off && (dir_name == "kubepods" || dir_name == "kubepods.slice") { continue }
+
+        let id = cgroup_name_to_id(&dir_name);
+        if is_container_id(id) {
+            *cgroup_dirs += 1;
+            add_container_cgroup(id, subdir.path(), out);
+        } else if depth > 1 {
+            find_container_cgroups_recursive(&subdir.path(), depth - 1, out, cgroup_dirs);
+        }
+    }
+}
+
+/// Looks for pod cgroups down to `depth` levels below `dir` (pods are grouped by QoS class, except Guaranteed
+/// ones), and adds either the pods or the containers in them depending on --kubepods.
+fn find_kubepods_cgroups(dir: &Path, depth: u8, out: &mut Vec<ContainerCgroup>) {
+    let Ok(subdirs) = fs::read_dir(dir) else { return };
+    for subdir in subdirs.filter_map(Result::ok) {
+        DIRS_SCANNED.set(DIRS_SCANNED.get() + 1);
+        if !subdir.file_type().is_ok_and(|t| t.is_dir()) { continue }
+        let dir_name = subdir.file_name();
+        let dir_name = dir_name.to_string_lossy();
+
+        // pod<uid> with the cgroupfs driver, kubepods-<qos>-pod<uid with _ instead of ->.slice with systemd
+        let name = dir_name.strip_suffix(".slice").unwrap_or(&dir_name);
+        let Some(uid) = name.rsplit_once("-pod").map(|(_, uid)| uid).or_else(|| name.strip_prefix("pod")) else {
+            if depth > 1 { find_kubepods_cgroups(&subdir.path(), depth - 1, out); }
+            continue;
+        };
+        let uid = uid.replace('_', "-");
+
+        if cfg().kubepods == KubepodsMode::Pod {
+            out.push(ContainerCgroup { id: uid.clone(), path: subdir.path(), parent_id: None, pod_uid: Some(uid) });
+            continue;
+        }
+        let Ok(containers) = fs::read_dir(subdir.path()) else { continue };
+        for container in containers.filter_map(Result::ok) {
+            DIRS_SCANNED.set(DIRS_SCANNED.get() + 1);
+            let container_name = container.file_name();
+            let container_name = container_name.to_string_lossy();
+            // <id> with cgroupfs, cri-containerd-<id>.scope, crio-<id>.scope, docker-<id>.scope etc. with systemd
+            let id = container_name.strip_suffix(".scope").unwrap_or(&container_name);
+            let id = id.rsplit('-').next().unwrap_or(id);
+            if is_container_id(id) && container.file_type().is_ok_and(|t| t.is_dir()) {
+                out.push(ContainerCgroup {
+                    id: id.to_owned(), path: container.path(), parent_id: None, pod_uid: Some(uid.clone())
+                });
             }
         }
+    }
+}
+
+/// Whether a container passes --name-regex-include/--name-regex-exclude, --label-selector, --exclude-container and
+/// --since-ms. Nested containers go with their parent.
+fn should_include_container(cont_id: &str) -> bool {
+    let (include, exclude) = (&cfg().name_regex_include_compiled, &cfg().name_regex_exclude_compiled);
+    let since_ms = cfg().since_ms;
+    let excluded_containers = &cfg().exclude_container;
+    let label_selectors = &cfg().label_selectors;
+    if include.is_none() && exclude.is_none() && since_ms == 0 && excluded_containers.is_empty() && label_selectors.is_empty() {
+        return true
+    }
 
-        match get_metrics(cpu_dir_sub.path()) {
-            Ok((usage_user_sec, usage_sys_sec, dir_name)) => {
-                let cont_id = dir_name_to_cont_id(&dir_name);
-                render_and_append_instance(&mut metric_user, usage_user_sec, cont_id);
-                render_and_append_instance(&mut metric_sys,  usage_sys_sec,  cont_id);
+    let mut map = crate::containers::CONTAINERS_MAP.lock().unwrap();
+    if !map.contains_key(cont_id) {
+        refresh_containers_map(&mut map);
+    }
+    for excluded in excluded_containers {
+        // Like the docker CLI: a full ID, then a name, and only then an ID prefix, so that a name like "cafe" doesn't
+        // also exclude whichever container's ID happens to start with it
+        if excluded == cont_id || map.get(cont_id).is_some_and(|cont| cont.name == *excluded) { return false }
+        if map.contains_key(excluded) || map.values().any(|cont| cont.name == *excluded) { continue }
+        match find_container_id(&map, excluded) {
+            Ok(Some(id)) if id == cont_id => return false,
+            Ok(_) => (),
+            Err(e) => if WARNED_AMBIGUOUS_IDS.lock().unwrap().insert(excluded.clone()) {
+                warn!("Not excluding anything for --exclude-container {excluded}: {e}.");
             }
-            Err(e) => error!("Metrics parsing error: {e}")
         }
     }
+    let Some(cont) = map.get(cont_id) else { return include.is_none() && since_ms == 0 && label_selectors.is_empty() };
 
-    let mut out = metric_user.render() + "\n";
-    out += &metric_sys.render();
-    Ok(out + "\n")
+    let has_labels = label_selectors.iter().all(|(key, value)| match (cont.config.labels.get(key), value) {
+        (Some(actual), Some(value)) => actual == value,
+        (Some(_), None) => true,
+        (None, _) => false
+    });
+    if !has_labels { return false }
+
+    if since_ms > 0 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        match cont.state.started_at_unix() {
+            Some(started_at) if now - started_at <= since_ms as f64 / 1000.0 => (),
+            _ => return false
+        }
+    }
+
+    let name = &cont.name;
+    include.as_ref().is_none_or(|r| r.is_match(name)) && !exclude.as_ref().is_some_and(|r| r.is_match(name))
 }
 
-fn get_blkio_metrics() -> Result<String> {
-    let mut metric_read = PrometheusMetric::build()
-        .with_name("container_blkio_read_total")
-        .with_metric_type(MetricType::Counter)
-        .with_help("Bytes read from disk by the container")
-        .build();
+/// Walks down to `depth` levels into a container's cgroup looking for containers nested inside it (sysbox, Docker
+/// in Docker, etc.). Those can use either cgroup driver regardless of what the host uses.
+fn find_nested_cgroups(dir: &Path, parent_id: &str, depth: u8, out: &mut Vec<ContainerCgroup>) {
+    // Directories can disappear at any moment here, so errors just mean there's nothing to find
+    let Ok(subdirs) = fs::read_dir(dir) else { return };
+    for subdir in subdirs.filter_map(Result::ok) {
+        DIRS_SCANNED.set(DIRS_SCANNED.get() + 1);
+        if !subdir.file_type().is_ok_and(|t| t.is_dir()) { continue }
+        let dir_name = subdir.file_name();
+        let dir_name = dir_name.to_string_lossy();
+        let id = cgroup_name_to_id(&dir_name);
 
-    let mut metric_write = PrometheusMetric::build()
-        .with_name("container_blkio_write_total")
-        .with_metric_type(MetricType::Counter)
-        .with_help("Bytes written to disk by the container")
-        .build();
+        if is_container_id(id) {
+            trace!("Found container {id} nested in {parent_id}.");
+            if depth > 1 { find_nested_cgroups(&subdir.path(), id, depth - 1, out); }
+            out.push(ContainerCgroup {
+                id: id.to_owned(), path: subdir.path(), parent_id: Some(parent_id.to_owned()), pod_uid: None
+            });
+        } else if depth > 1 {
+            find_nested_cgroups(&subdir.path(), parent_id, depth - 1, out);
+        }
+    }
+}
+
+/// What one collector did during its last run, for /debug/timings.
+#[derive(Clone, Copy, Default)]
+struct CollectorStats {
+    duration: Duration,
+    dirs_scanned: u64,
+    files_read: u64
+}
+
+lazy_static! {
+    /// Pairs of colliding container labels that have already been warned about, so it's only logged once
+    static ref WARNED_LABEL_COLLISIONS: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+    /// Container labels that have already been warned about for not making a usable metric label name
+    static ref WARNED_INVALID_LABELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// Cgroup directory names that have already been warned about for not containing a valid container ID
+    static ref WARNED_INVALID_IDS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref WARNED_AMBIGUOUS_IDS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref COLLECTOR_STATS: Mutex<BTreeMap<&'static str, CollectorStats>> = Mutex::new(BTreeMap::new());
+    /// Block device names by major:minor, for --blkio-device-names
+    static ref DEVICE_NAMES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
 
-    let blkio_dirs = fs::read_dir(&*BLKIO_DIR).unwrap_or_else(|_| panic!("Couldn't read blkio directory {:?}", *BLKIO_DIR));
-    for blkio_dir_sub in blkio_dirs.filter_map(Result::ok) {
-        if !blkio_dir_sub.file_type().unwrap().is_dir()
-            || blkio_dir_sub.file_name().len() != *EXPECTED_DIR_NAME_LEN { continue }
+thread_local! {
+    // Collectors run start to finish on one thread, so these count for whichever collector is running on this one
+    static DIRS_SCANNED: Cell<u64> = const { Cell::new(0) };
+    static FILES_READ: Cell<u64> = const { Cell::new(0) };
+    // match[] selectors of the request being served on this thread, see [set_selectors]
+    static SELECTORS: RefCell<Vec<Selector>> = const { RefCell::new(Vec::new()) };
+    // ?id= of the request being served on this thread, see [get_metrics_string]
+    static ID_FILTER: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Metric family names by the address of their PrometheusMetric, see [family_name]. Cleared for every collector
+    // run, so an address can't be reused by another family in the meantime.
+    static FAMILY_NAMES: RefCell<HashMap<usize, String>> = RefCell::new(HashMap::new());
+    // Per-container errors (see [ScrapeErrors]) in the scrape running on this thread, see [scrape_error_count]
+    static SCRAPE_ERROR_COUNT: Cell<usize> = const { Cell::new(0) };
+}
 
-        fn get_metrics(dir: PathBuf) -> Result<(u64, u64, String)> {
-            let dir_name = dir.file_name().unwrap().to_owned().into_string()
-                .map_err(|x| Error::msg(format!("Failed to read dirname {:?}", x)))?;
+/// How many per-container errors the last [get_metrics_string] on this thread ran into. Those only get logged,
+/// the scrape itself still works.
+pub fn scrape_error_count() -> usize {
+    SCRAPE_ERROR_COUNT.get()
+}
 
-            let mut total_read:  u64 = 0;
-            let mut total_write: u64 = 0;
+/// Only export container series matching one of these selectors (all of them if empty), for requests served on
+/// this thread from now on. This is how match[] query parameters make it into the collectors.
+pub fn set_selectors(selectors: Vec<Selector>) {
+    SELECTORS.set(selectors);
+}
 
-            if *CGROUP_VER == CgroupVersion::V1 {
-                let io_service_bytes = fs::read_to_string(dir.join("blkio.throttle.io_service_bytes"))?;
-                for line in io_service_bytes.lines() {
-                    if line.contains("Read") {
-                        total_read += line.split_ascii_whitespace().last()
-                            .ok_or(Error::msg("Couldn't split Read line in blkio.throttle.io_service_bytes"))?.parse::<u64>()?;
-                    } else if line.contains("Write") {
-                        total_write += line.split_ascii_whitespace().last()
-                            .ok_or(Error::msg("Couldn't split Write line in blkio.throttle.io_service_bytes"))?.parse::<u64>()?;
-                    }
+/// Runs a collector, keeping track of how long it took and how much it read for /debug/timings.
+fn run_collector(name: &'static str, collector: Collector) -> Result<String> {
+    DIRS_SCANNED.set(0);
+    FILES_READ.set(0);
+    FAMILY_NAMES.with_borrow_mut(HashMap::clear);
+    let start = Instant::now();
+    // Leave out the whole family, headers and all, when there's no controller for it to read
+    // One setup for the whole collector, even if it gets re-detected in the meantime
+    let setup = cgroups();
+    let result = if setup.missing_controller(name).is_none() { collector(&setup) } else { Ok(String::new()) };
+    let result = result
+        .map(|output| if cfg().aggregate_labels.is_some() { aggregate_duplicate_series(&output) } else { output })
+        .map(|output| if cfg().raw_counters { strip_counter_timestamps(&output) } else { output });
+    let stats = CollectorStats {
+        duration: start.elapsed(),
+        dirs_scanned: DIRS_SCANNED.get(),
+        files_read: FILES_READ.get()
+    };
+    COLLECTOR_STATS.lock().unwrap().insert(name, stats);
+    result
+}
+
+/// How --aggregate-by combines the samples of all containers of a group into one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Aggregation { Sum, Min, Max }
+
+/// Which [Aggregation] a family gets. Counters and usage gauges add up; a sum of limits, flags or start times
+/// means nothing, so those get the largest value (the earliest one for start times) instead.
+fn aggregation_for(family: &str, metric_type: &str) -> Aggregation {
+    if metric_type == "counter" { return Aggregation::Sum }
+    let additive = (family.starts_with("container_memory_") && !family.starts_with("container_memory_limit_"))
+        || matches!(family, "container_processes" | "container_cpu_runqueue_wait_seconds" | "container_cpu_iowait_seconds"
+            | "container_info" | "container_cpuset_info" | "container_restart_count");
+    match family {
+        _ if additive => Aggregation::Sum,
+        "container_start_time_seconds" => Aggregation::Min,
+        _ => Aggregation::Max
+    }
+}
+
+/// Combines samples of the same series in a collector's output, for --aggregate-by, as [aggregation_for] says.
+/// The first sample's timestamp is kept.
+fn aggregate_duplicate_series(output: &str) -> String {
+    let mut out = String::with_capacity(output.len());
+    for family in output.split_inclusive("\n\n") {
+        let mut series: Vec<(&str, f64, &str)> = Vec::new();
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        let mut aggregation = Aggregation::Sum;
+        for line in family.lines() {
+            if line.is_empty() { continue }
+            if line.starts_with('#') {
+                if let Some((name, metric_type)) = line.strip_prefix("# TYPE ").and_then(|t| t.split_once(' ')) {
+                    aggregation = aggregation_for(name, metric_type);
                 }
-            } else {
-                let io_stat = fs::read_to_string(dir.join("io.stat"))?;
-                for line in io_stat.lines() {
-                    for kv in line.split_ascii_whitespace() {
-                        if kv.contains('=') {
-                            let mut spl = kv.split('=');
-                            let first = spl.next().ok_or(Error::msg("Couldn't split kv pair in io.stat"))?;
-                            let last: u64 = spl.last().ok_or(Error::msg("Couldn't split kv pair in io.stat"))?.parse()?;
-                            match first {
-                                "rbytes" => total_read  += last,
-                                "wbytes" => total_write += last,
-                                _ => ()
-                            }
-                        }
-                    }
+                out += line;
+                out.push('\n');
+                continue;
+            }
+            // Label values can have spaces, but the value and timestamp can't
+            let mut fields = line.rsplitn(3, ' ');
+            let (Some(timestamp), Some(value), Some(name_and_labels)) = (fields.next(), fields.next(), fields.next()) else {
+                warn!("Can't aggregate malformed sample {line:?}");
+                continue;
+            };
+            let value: f64 = value.parse().unwrap_or(f64::NAN);
+            match index.get(name_and_labels) {
+                Some(&i) => series[i].1 = match aggregation {
+                    Aggregation::Sum => series[i].1 + value,
+                    Aggregation::Min => series[i].1.min(value),
+                    Aggregation::Max => series[i].1.max(value)
+                },
+                None => {
+                    index.insert(name_and_labels, series.len());
+                    series.push((name_and_labels, value, timestamp));
                 }
             }
+        }
+        for (name_and_labels, value, timestamp) in series {
+            out += &format!("{name_and_labels} {value} {timestamp}\n");
+        }
+        if family.ends_with("\n\n") { out.push('\n') }
+    }
+    out
+}
 
-            Ok((total_read, total_write, dir_name))
+/// Drops the timestamps from all counter samples in a collector's output, for --raw-counters.
+fn strip_counter_timestamps(output: &str) -> String {
+    let mut out = String::with_capacity(output.len());
+    for family in output.split_inclusive("\n\n") {
+        let is_counter = family.lines().any(|l| l.strip_prefix("# TYPE ").is_some_and(|t| t.ends_with(" counter")));
+        if !is_counter { out += family; continue }
+        for line in family.split_inclusive('\n') {
+            // Samples always have a timestamp at this point, and label values can have spaces, so it's the last field
+            match line.strip_suffix('\n').unwrap_or(line).rsplit_once(' ') {
+                Some((sample, _timestamp)) if !line.starts_with('#') => { out += sample; out.push('\n') }
+                _ => out += line
+            }
         }
+    }
+    out
+}
+
+/// Plain text table of what each collector did during its last run.
+pub fn get_debug_timings() -> String {
+    let stats = COLLECTOR_STATS.lock().unwrap();
+    let mut out = format!("{:<12} {:>12} {:>13} {:>11}\n", "collector", "duration_ms", "dirs_scanned", "files_read");
+    for (name, stat) in stats.iter() {
+        out += &format!("{:<12} {:>12.3} {:>13} {:>11}\n",
+            name, stat.duration.as_secs_f64() * 1000.0, stat.dirs_scanned, stat.files_read);
+    }
+    out
+}
+
+/// Runs all collectors. With an `id`, only the cgroups of containers whose ID starts with it are read, so the
+/// output can be just the metric headers if there's no such container.
+pub fn get_metrics_string(id: Option<&str>) -> Result<String> {
+    ID_FILTER.set(id.map(str::to_owned));
+    SCRAPE_ERROR_COUNT.set(0);
+    let start = Instant::now();
+    let max_bytes = cfg().max_response_bytes;
+    let mut output = String::with_capacity(1024);
+    let mut truncated = false;
+    let mut samples: Vec<(String, usize)> = Vec::new();
+    // The build info goes at the top of every response, so it counts against the limit too. The scrape duration
+    // isn't known yet, so leave room for the longest it could be written as.
+    let build_info_len = get_build_info_metrics(Duration::ZERO).len() + MAX_F64_DISPLAY_LEN;
+    'collectors: for (name, collector) in COLLECTORS {
+        // Each metric family is rendered followed by a blank line, so this splits on family boundaries
+        for family in run_collector(name, *collector)?.split_inclusive("\n\n") {
+            let family_name = family.lines().find_map(|l| l.strip_prefix("# TYPE ")?.split(' ').next());
+            if let Some(family_name) = family_name {
+                let count = family.lines().filter(|l| !l.is_empty() && !l.starts_with('#')).count();
+                samples.push((family_name.to_owned(), count));
+            }
 
-        match get_metrics(blkio_dir_sub.path()) {
-            Ok((total_read, total_write, dir_name)) => {
-                let cont_id = dir_name_to_cont_id(&dir_name);
-                render_and_append_instance(&mut metric_read, total_read, cont_id);
-                render_and_append_instance(&mut metric_write, total_write, cont_id);
+            // The exporter metrics at the end have to fit too, and get a line for each family that's in
+            if max_bytes > 0 {
+                let exporter_len = get_exporter_metrics(true, &samples).len();
+                if build_info_len + output.len() + family.len() + exporter_len > max_bytes {
+                    warn!("Metrics output would exceed --max-response-bytes ({max_bytes}), truncating it.");
+                    truncated = true;
+                    if family_name.is_some() { samples.pop(); }
+                    break 'collectors;
+                }
             }
-            Err(e) => error!("Metrics parsing error: {e}")
+            output += family;
         }
     }
+    output += &get_exporter_metrics(truncated, &samples);
+    Ok(get_build_info_metrics(start.elapsed()) + &output)
+}
 
-    let mut out = metric_read.render() + "\n";
-    out += &metric_write.render();
-    Ok(out + "\n")
+/// Runs just the one collector with the given name, or returns None if there's no such collector. `id` works like
+/// in [get_metrics_string].
+pub fn get_collector_metrics_string(name: &str, id: Option<&str>) -> Option<Result<String>> {
+    ID_FILTER.set(id.map(str::to_owned));
+    COLLECTORS.iter().find(|(n, _)| *n == name).map(|(name, collector)| run_collector(name, *collector))
 }
 
-fn render_and_append_instance<N: num::Num + std::fmt::Display + core::fmt::Debug>(metric: &mut PrometheusMetric<'_>, value: N, cont_id: &str) {
-    let mut prom = PrometheusInstance::new()
-        .with_value(value)
-        .with_label("id", cont_id)
-        .with_current_timestamp()
-        .expect("error getting UNIX time for timestamp");
+/// Whether there's a collector with this name, for /metrics/<name>.
+pub fn is_collector(name: &str) -> bool {
+    COLLECTORS.iter().any(|(n, _)| *n == name)
+}
 
-    let mut map = crate::containers::CONTAINERS_MAP.lock().unwrap();
-    let label_keys: append_only_vec::AppendOnlyVec<String> = append_only_vec::AppendOnlyVec::new();
+/// Does one full scrape and checks it makes sense for --selftest: if Docker says containers are running, we should
+/// have found at least some of them in the cgroupfs. Logs what's wrong, if anything.
+pub fn selftest() -> bool {
+    print_cgroup_detection_results();
+    if !expand_cgroup_dir(&cgroups().memory_dir).iter().any(|dir| dir.is_dir()) {
+        error!("Self-test failed: cgroup directory {:?} doesn't exist. Is cgroup version {:?} with the {:?} driver right? \
+            See --cgroup-version and --docker-cgroup-driver.", cgroups().memory_dir, cgroups().version, cgroups().driver);
+        return false;
+    }
 
-    if !map.contains_key(cont_id) {
-        refresh_containers_map(&mut map);
+    let output = match get_metrics_string(None) {
+        Ok(output) => output,
+        Err(e) => { error!("Self-test failed, couldn't get metrics: {e}"); return false }
+    };
+    let (running, samples) = (running_container_count(), container_sample_count(&output));
+    let invalid_ids = INVALID_CONTAINER_IDS.load(Ordering::Relaxed);
+    info!("Self-test: {running} running containers in {:?}, {samples} container samples from {:?}, \
+        {invalid_ids} cgroup directories with invalid container IDs.", cfg().containers_dir, cgroups().memory_dir);
+
+    if scrape_found_nothing(&output) {
+        error!("Self-test failed: Docker has running containers, but none were found in the cgroupfs. \
+            Is cgroup version {:?} with the {:?} driver right? See --cgroup-version and --docker-cgroup-driver.",
+            cgroups().version, cgroups().driver);
+        return false;
+    }
+    if invalid_ids > 0 {
+        warn!("Some cgroup directories didn't contain a valid container ID, the cgroup driver might be detected wrong.");
     }
+    info!("Self-test passed.");
+    true
+}
 
-    let include_labels = &cfg().include_labels_set;
-    let exclude_labels = &cfg().exclude_labels_set;
-    
-    if let Some(cont) = map.get(cont_id) {
-        prom = prom
-            .with_label("name", &*cont.name)
-            .with_label("image", &*cont.config.image);
-
-        for (label_key, label_val) in &cont.config.labels {
-            trace!("Inserting label {} ...", label_key);
-            if !include_labels.is_empty() {
-                if !include_labels.contains(label_key) { trace!("Not included."); continue; }
-            } else if !exclude_labels.is_empty() && exclude_labels.contains(label_key) {
-                trace!("Excluded.");
-                continue;
-            }
-            let key = format!("container_label_{}", label_key).replace('.', "_").replace('-', "_");
-            let idx = label_keys.push(key);
-            prom = prom.with_label(&*label_keys[idx], label_val.as_str());
+/// Whether a full, unfiltered scrape came out empty even though Docker says containers are running, meaning
+/// something's wrong with reading the cgroupfs.
+pub fn scrape_found_nothing(output: &str) -> bool {
+    container_sample_count(output) == 0 && running_container_count() > 0
+}
+
+fn container_sample_count(output: &str) -> usize {
+    // With --aggregate-by there's no id label, but only container metrics start with container_ anyway.
+    // The ones that come straight from Docker's metadata don't say anything about whether the cgroupfs works.
+    output.lines()
+        .filter(|l| l.starts_with("container_"))
+        .filter(|l| !METADATA_ONLY_FAMILIES.iter().any(|family| l.strip_prefix(family).is_some_and(|rest| rest.starts_with(['{', ' ']))))
+        .count()
+}
+
+/// Number of running containers we should be exporting, i.e. that aren't filtered out.
+fn running_container_count() -> usize {
+    let running: Vec<String> = crate::containers::CONTAINERS_MAP.lock().unwrap().values()
+        .filter(|c| c.state.running).map(|c| c.id.clone()).collect();
+    running.iter().filter(|id| should_include_container(id)).count()
+}
+
+/// Counts a scrape, for dockerprom_scrapes_total and dockerprom_last_scrape_timestamp_seconds.
+pub fn record_scrape() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    SCRAPES.fetch_add(1, Ordering::Relaxed);
+    LAST_SCRAPE_MS.store(now.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Counts a scrape with --serialize-scrapes, by whether it reused another request's result or ran by itself.
+pub fn record_serialized_scrape(shared: bool) {
+    if shared { &SERIALIZED_SCRAPES_SHARED } else { &SERIALIZED_SCRAPES_RUN }.fetch_add(1, Ordering::Relaxed);
+}
+
+/// UNIX time in milliseconds of the most recent scrape, or 0 if there hasn't been one yet.
+pub fn last_scrape_ms() -> u64 {
+    LAST_SCRAPE_MS.load(Ordering::Relaxed)
+}
+
+/// dockerprom_build_info and dockerprom_scrape_duration_seconds, which go at the top of the output.
+fn get_build_info_metrics(scrape_duration: Duration) -> String {
+    let mut metric_build_info = PrometheusMetric::build()
+        .with_name("dockerprom_build_info")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Always 1, labeled with the exporter's version and the Rust version it was built with")
+        .build();
+    metric_build_info.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(1)
+        .with_label("version", env!("CARGO_PKG_VERSION"))
+        .with_label("rust_version", env!("DOCKERPROM_RUSTC_VERSION")));
+
+    let mut metric_scrape_duration = PrometheusMetric::build()
+        .with_name("dockerprom_scrape_duration_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("How long reading all the container metrics took for this scrape")
+        .build();
+    metric_scrape_duration.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(scrape_duration.as_secs_f64()));
+
+    metric_build_info.render() + "\n" + &metric_scrape_duration.render() + "\n"
+}
+
+/// Metrics about the exporter itself, rather than any container. `samples` is how many samples each metric family
+/// had in this scrape.
+fn get_exporter_metrics(truncated: bool, samples: &[(String, usize)]) -> String {
+    let mut metric_invalid_ids = PrometheusMetric::build()
+        .with_name("dockerprom_invalid_container_ids_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Cgroup directories skipped because their name didn't contain a valid container ID")
+        .build();
+    metric_invalid_ids.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(INVALID_CONTAINER_IDS.load(Ordering::Relaxed)));
+
+    let mut metric_cgroup_info = PrometheusMetric::build()
+        .with_name("dockerprom_cgroup_info")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("The cgroup version and Docker cgroup driver this exporter is assuming")
+        .build();
+    let setup = cgroups();
+    let version = setup.version.to_possible_value().unwrap();
+    let driver = setup.driver.to_possible_value().unwrap();
+    metric_cgroup_info.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(1)
+        .with_label("version", version.get_name())
+        .with_label("driver", driver.get_name()));
+
+    let mut metric_containers = PrometheusMetric::build()
+        .with_name("dockerprom_containers_total")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Containers found in the containers directory during the last metadata refresh")
+        .build();
+    let containers = crate::containers::CONTAINERS_MAP.lock().unwrap().len();
+    metric_containers.render_and_append_instance(&PrometheusInstance::new().with_value(containers));
+
+    let mut metric_cgroup_dirs = PrometheusMetric::build()
+        .with_name("dockerprom_cgroup_dirs_total")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Directories that looked like container cgroups in the last scan of the memory controller")
+        .build();
+    metric_cgroup_dirs.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(CGROUP_DIRS.load(Ordering::Relaxed)));
+
+    let mut metric_metadata_readable = PrometheusMetric::build()
+        .with_name("dockerprom_metadata_dir_readable")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Whether the containers directory could be read during the last metadata refresh")
+        .build();
+    metric_metadata_readable.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(METADATA_DIR_READABLE.load(Ordering::Relaxed) as u8));
+
+    let mut metric_truncated = PrometheusMetric::build()
+        .with_name("dockerprom_response_truncated")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Whether some metric families were left out of this response because of --max-response-bytes")
+        .build();
+    metric_truncated.render_and_append_instance(&PrometheusInstance::new().with_value(truncated as u8));
+
+    let mut metric_scrapes = PrometheusMetric::build()
+        .with_name("dockerprom_scrapes_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Number of times this exporter has been scraped for metrics")
+        .build();
+    metric_scrapes.render_and_append_instance(&PrometheusInstance::new().with_value(SCRAPES.load(Ordering::Relaxed)));
+
+    let mut metric_last_scrape = PrometheusMetric::build()
+        .with_name("dockerprom_last_scrape_timestamp_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("UNIX time of the most recent scrape of this exporter")
+        .build();
+    metric_last_scrape.render_and_append_instance(&PrometheusInstance::new()
+        .with_value(LAST_SCRAPE_MS.load(Ordering::Relaxed) as f64 / 1000.0));
+
+    let mut metric_serialized = PrometheusMetric::build()
+        .with_name("dockerprom_serialized_scrapes_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Scrapes with --serialize-scrapes, by whether they ran or shared the result of one that just ran")
+        .build();
+    if cfg().serialize_scrapes {
+        for (outcome, count) in [("ran", &SERIALIZED_SCRAPES_RUN), ("shared", &SERIALIZED_SCRAPES_SHARED)] {
+            metric_serialized.render_and_append_instance(&PrometheusInstance::new()
+                .with_value(count.load(Ordering::Relaxed)).with_label("outcome", outcome));
         }
-    } else {
-        warn!("Couldn't find details for container ID {cont_id}");
     }
 
-    metric.render_and_append_instance(&prom);
-}
\ No newline at end of file
+    let mut metric_samples = PrometheusMetric::build()
+        .with_name("dockerprom_samples_exported")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Number of samples (series) exported in this scrape, per metric family")
+        .build();
+    for (family, count) in samples {
+        metric_samples.render_and_append_instance(&PrometheusInstance::new().with_value(*count).with_label("family", family.as_str()));
+    }
+
+    let mut out = metric_invalid_ids.render() + "\n";
+    out += &metric_scrapes.render();
+    out += "\n";
+    out += &metric_last_scrape.render();
+    out += "\n";
+    out += &metric_cgroup_info.render();
+    out += "\n";
+    out += &metric_containers.render();
+    out += "\n";
+    out += &metric_cgroup_dirs.render();
+    out += "\n";
+    out += &metric_metadata_readable.render();
+    out += "\n";
+    out += &metric_truncated.render();
+    out += "\n";
+    out += &metric_samples.render();
+    out += "\n";
+    out += &metric_serialized.render();
+    out + "\n"
+}
+
+/// Metric name for a memory metric, given its name in bytes. Ends in _mib instead of _bytes with --memory-unit mib.
+fn memory_metric_name(name: &str) -> String {
+    match cfg().memory_unit {
+        MemoryUnit::Bytes => name.to_owned(),
+        MemoryUnit::Mib => format!("{}_mib", name.strip_suffix("_bytes").unwrap_or(name))
+    }
+}
+
+/// Converts a number of bytes to the --memory-unit.
+fn scale_memory(bytes: u64) -> f64 {
+    match cfg().memory_unit {
+        MemoryUnit::Bytes => bytes as f64,
+        MemoryUnit::Mib => bytes as f64 / (1024.0 * 1024.0)
+    }
+}
+
+fn memory_unit_name() -> &'static str {
+    match cfg().memory_unit { MemoryUnit::Bytes => "bytes", MemoryUnit::Mib => "MiB" }
+}
+
+/// Metric name for a CPU time counter, given its name in seconds. Ends in _ms_total with --cpu-unit ms.
+fn cpu_metric_name(name: &str) -> String {
+    match cfg().cpu_unit {
+        CpuUnit::Seconds => name.to_owned(),
+        CpuUnit::Ms => format!("{}_ms_total", name.strip_suffix("_total").unwrap_or(name).trim_end_matches("_seconds"))
+    }
+}
+
+/// Converts a number of seconds to the --cpu-unit.
+fn scale_cpu(seconds: f64) -> f64 {
+    match cfg().cpu_unit { CpuUnit::Seconds => seconds, CpuUnit::Ms => seconds * 1000.0 }
+}
+
+fn cpu_unit_name() -> &'static str {
+    match cfg().cpu_unit { CpuUnit::Seconds => "seconds", CpuUnit::Ms => "milliseconds" }
+}
+
+fn get_memory_metric(setup: &CgroupSetup) -> Result<String> {
+    let name_rss = memory_metric_name("container_memory_usage");
+    let help_rss = format!("Memory used by the container, in {}", memory_unit_name());
+    let mut metric_rss = PrometheusMetric::build()
+        .with_name(&name_rss)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_rss)
+        .build();
+
+    let name_limit = memory_metric_name("container_memory_limit_bytes");
+    let help_limit = format!("Memory limit of the container, in {}. Not there for containers without a limit", memory_unit_name());
+    let mut metric_limit = PrometheusMetric::build()
+        .with_name(&name_limit)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_limit)
+        .build();
+
+    let name_shmem = memory_metric_name("container_memory_shmem_bytes");
+    let help_shmem = format!("Shared memory and tmpfs used by the container, in {}", memory_unit_name());
+    let mut metric_shmem = PrometheusMetric::build()
+        .with_name(&name_shmem)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_shmem)
+        .build();
+
+    let name_mapped = memory_metric_name("container_memory_mapped_file_bytes");
+    let help_mapped = format!("Memory-mapped files (incl. tmpfs and shared memory) of the container, in {}", memory_unit_name());
+    let mut metric_mapped = PrometheusMetric::build()
+        .with_name(&name_mapped)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_mapped)
+        .build();
+
+    // Page cache of files shared between containers (like common image layers) is charged to one container at a
+    // time, and which one can change, so there's no deduplicating it from here. Exporting it separately at least
+    // lets people tell how much of each container's usage is cache.
+    let name_cache = memory_metric_name("container_memory_cache_bytes");
+    let help_cache = format!("Page cache (file-backed memory) of the container, in {}", memory_unit_name());
+    let mut metric_cache = PrometheusMetric::build()
+        .with_name(&name_cache)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_cache)
+        .build();
+
+    let name_zswap = memory_metric_name("container_memory_zswap_bytes");
+    let help_zswap = format!("Compressed swap (zswap) used by the container, in {}", memory_unit_name());
+    let mut metric_zswap = PrometheusMetric::build()
+        .with_name(&name_zswap)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_zswap)
+        .build();
+
+    let name_swap = memory_metric_name("container_memory_swap_bytes");
+    let help_swap = format!("Swap used by the container, in {}. Not there without swap accounting", memory_unit_name());
+    let mut metric_swap = PrometheusMetric::build()
+        .with_name(&name_swap)
+        .with_metric_type(MetricType::Gauge)
+        .with_help(&help_swap)
+        .build();
+
+    let mut metric_oom_kills = PrometheusMetric::build()
+        .with_name("container_oom_kills_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Number of processes in the container killed by the OOM killer")
+        .build();
+
+    let mut errors = ScrapeErrors::new("memory");
+    for cgroup in container_cgroups(setup, &setup.memory_dir) {
+        let usage_file = match setup.version { CgroupVersion::V1 => "memory.usage_in_bytes", CgroupVersion::V2 => "memory.current" };
+        let memory_usage = read_cgroup_file(cgroup.path.join(usage_file))
+            .map_err(Error::from)
+            .and_then(|usage| Ok(usage.trim_end().parse::<u64>()?));
+        // Most likely the container exited while we were scraping, so there's nothing else to read for it either
+        let memory_usage = match memory_usage {
+            Ok(memory_usage) => memory_usage,
+            Err(e) => { errors.push(&cgroup, e); continue }
+        };
+        render_and_append_instance(&mut metric_rss, scale_memory(memory_usage), &cgroup);
+
+        // The files are only there with swap accounting, so a container without them just gets no swap metric
+        let swap_file = match setup.version { CgroupVersion::V1 => "memory.memsw.usage_in_bytes", CgroupVersion::V2 => "memory.swap.current" };
+        match read_optional_cgroup_file(cgroup.path.join(swap_file)) {
+            Ok(Some(swap)) => match swap.trim_end().parse::<u64>() {
+                // v1 counts memory plus swap, v2 just swap
+                Ok(swap) => {
+                    let swap = match setup.version { CgroupVersion::V1 => swap.saturating_sub(memory_usage), CgroupVersion::V2 => swap };
+                    render_and_append_instance(&mut metric_swap, scale_memory(swap), &cgroup)
+                }
+                Err(e) => errors.push(&cgroup, e.into())
+            },
+            Ok(None) => (),
+            Err(e) => errors.push(&cgroup, e.into())
+        }
+
+        let limit_file = match setup.version { CgroupVersion::V1 => "memory.limit_in_bytes", CgroupVersion::V2 => "memory.max" };
+        match read_cgroup_file(cgroup.path.join(limit_file)) {
+            Ok(limit) => match parse_cgroup_limit(&limit) {
+                Ok(Some(limit)) => render_and_append_instance(&mut metric_limit, scale_memory(limit), &cgroup),
+                Ok(None) => (),
+                Err(e) => errors.push(&cgroup, e)
+            },
+            Err(e) => errors.push(&cgroup, e.into())
+        }
+
+        match read_memory_stat(&cgroup.path) {
+            Ok(stat) => {
+                // v1 has the container's own numbers and total_ ones including child cgroups, v2 only the latter
+                let (shmem_key, mapped_key, cache_key) = match setup.version {
+                    CgroupVersion::V1 => ("total_shmem", "total_mapped_file", "total_cache"),
+                    CgroupVersion::V2 => ("shmem", "file_mapped", "file")
+                };
+                if let Some(&shmem) = stat.get(shmem_key) {
+                    render_and_append_instance(&mut metric_shmem, scale_memory(shmem), &cgroup);
+                }
+                if let Some(&mapped) = stat.get(mapped_key) {
+                    render_and_append_instance(&mut metric_mapped, scale_memory(mapped), &cgroup);
+                }
+                if let Some(&cache) = stat.get(cache_key) {
+                    render_and_append_instance(&mut metric_cache, scale_memory(cache), &cgroup);
+                }
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+
+        // Same "key value" lines in both, but v1 kernels before 4.13 don't have the oom_kill field
+        let events_file = match setup.version { CgroupVersion::V1 => "memory.oom_control", CgroupVersion::V2 => "memory.events" };
+        match read_oom_kills(&cgroup.path.join(events_file)) {
+            Ok(Some(oom_kills)) => render_and_append_instance(&mut metric_oom_kills, oom_kills, &cgroup),
+            Ok(None) => (),
+            Err(e) => errors.push(&cgroup, e)
+        }
+
+        // Only there on v2 with zswap enabled
+        if setup.version == CgroupVersion::V2 {
+            match read_cgroup_file(cgroup.path.join("memory.zswap.current")) {
+                Ok(zswap) => match zswap.trim_end().parse::<u64>() {
+                    Ok(zswap) => render_and_append_instance(&mut metric_zswap, scale_memory(zswap), &cgroup),
+                    Err(e) => errors.push(&cgroup, e.into())
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => errors.push(&cgroup, e.into())
+            }
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_rss.render() + "\n";
+    out += &(metric_limit.render() + "\n");
+    out += &(metric_shmem.render() + "\n");
+    out += &(metric_mapped.render() + "\n");
+    out += &(metric_cache.render() + "\n");
+    out += &(metric_zswap.render() + "\n");
+    out += &(metric_swap.render() + "\n");
+    out += &metric_oom_kills.render();
+    Ok(out + "\n")
+}
+
+/// Gets the oom_kill count out of a memory.oom_control or memory.events file, if it has one.
+fn read_oom_kills(path: &Path) -> Result<Option<u64>> {
+    for line in read_cgroup_file(path)?.lines() {
+        if let Some(("oom_kill", value)) = line.split_once(' ') {
+            return Ok(Some(value.trim().parse()?));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a cgroup's memory.stat into a map of its keys to their values.
+fn read_memory_stat(dir: &Path) -> Result<HashMap<String, u64>> {
+    let mut stat = HashMap::new();
+    for line in read_cgroup_file(dir.join("memory.stat"))?.lines() {
+        let (key, value) = line.split_once(' ').ok_or(Error::msg("Couldn't split line in memory.stat"))?;
+        stat.insert(key.to_owned(), value.trim().parse()?);
+    }
+    Ok(stat)
+}
+
+fn get_pids_metric(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_processes = PrometheusMetric::build()
+        .with_name("container_processes")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Number of processes (and threads) in the container")
+        .build();
+
+    let mut errors = ScrapeErrors::new("pids");
+    for cgroup in container_cgroups(setup, &setup.pids_dir) {
+        match read_optional_cgroup_file(cgroup.path.join("pids.current")) {
+            Ok(Some(pids)) => match pids.trim_end().parse::<u64>() {
+                Ok(pids) => render_and_append_instance(&mut metric_processes, pids, &cgroup),
+                Err(e) => errors.push(&cgroup, e.into())
+            },
+            Ok(None) => trace!("No pids.current for container {}, is the pids controller enabled?", cgroup.id),
+            Err(e) => errors.push(&cgroup, e.into())
+        }
+    }
+    errors.log_summary();
+
+    Ok(metric_processes.render() + "\n")
+}
+
+fn get_cpu_metrics(setup: &CgroupSetup) -> Result<String> {
+    let name_user = cpu_metric_name("container_cpu_user_total");
+    let help_user = format!("CPU {} used by the container in userspace", cpu_unit_name());
+    let mut metric_user = PrometheusMetric::build()
+        .with_name(&name_user)
+        .with_metric_type(MetricType::Counter)
+        .with_help(&help_user)
+        .build();
+
+    let name_sys = cpu_metric_name("container_cpu_system_total");
+    let help_sys = format!("CPU {} used by the container in kernelspace", cpu_unit_name());
+    let mut metric_sys = PrometheusMetric::build()
+        .with_name(&name_sys)
+        .with_metric_type(MetricType::Counter)
+        .with_help(&help_sys)
+        .build();
+
+    let mut metric_burst_periods = PrometheusMetric::build()
+        .with_name("container_cpu_burst_periods_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Number of CFS periods in which the container used its CPU burst allowance")
+        .build();
+
+    let name_burst = cpu_metric_name("container_cpu_burst_seconds_total");
+    let help_burst = format!("CPU {} the container used beyond its quota thanks to CFS burst", cpu_unit_name());
+    let mut metric_burst = PrometheusMetric::build()
+        .with_name(&name_burst)
+        .with_metric_type(MetricType::Counter)
+        .with_help(&help_burst)
+        .build();
+
+    let mut metric_throttled_periods = PrometheusMetric::build()
+        .with_name("container_cpu_throttled_periods_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Number of CFS periods in which the container was throttled for hitting its CPU quota")
+        .build();
+
+    let name_throttled = cpu_metric_name("container_cpu_throttled_seconds_total");
+    let help_throttled = format!("Total {} the container spent throttled for hitting its CPU quota", cpu_unit_name());
+    let mut metric_throttled = PrometheusMetric::build()
+        .with_name(&name_throttled)
+        .with_metric_type(MetricType::Counter)
+        .with_help(&help_throttled)
+        .build();
+
+    let mut metric_burst_limit = PrometheusMetric::build()
+        .with_name("container_spec_cpu_burst")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("CFS burst the container is allowed to accumulate beyond its quota, in microseconds")
+        .build();
+
+    let name_percpu = cpu_metric_name("container_cpu_percpu_seconds_total");
+    let help_percpu = format!("CPU {} used by the container on each CPU core", cpu_unit_name());
+    let mut metric_percpu = PrometheusMetric::build()
+        .with_name(&name_percpu)
+        .with_metric_type(MetricType::Counter)
+        .with_help(&help_percpu)
+        .build();
+
+    if cfg().percpu && setup.version == CgroupVersion::V2 && !WARNED_NO_PERCPU.swap(true, Ordering::Relaxed) {
+        warn!("cgroup v2 doesn't keep per-CPU usage, there won't be any container_cpu_percpu_seconds_total.");
+    }
+
+    struct CpuUsage {
+        user_sec: f64,
+        sys_sec: f64,
+        /// Number of burst periods and seconds of burst used, on kernels that support CFS burst
+        burst: Option<(u64, f64)>,
+        /// Configured cpu.max.burst in microseconds, on kernels that support CFS burst
+        burst_limit_us: Option<u64>,
+        /// Number of throttled periods and seconds spent throttled, if the cpu controller has a cpu.stat
+        throttled: Option<(u64, f64)>,
+        /// Seconds used on each CPU, with --percpu on v1
+        percpu_sec: Option<Vec<f64>>
+    }
+
+    let mut errors = ScrapeErrors::new("cpu");
+    let usage_dir = match setup.version { CgroupVersion::V1 => &setup.cpuacct_dir, CgroupVersion::V2 => &setup.cpu_dir };
+    for cgroup in container_cgroups(setup, usage_dir) {
+        fn get_metrics(setup: &CgroupSetup, dir: &Path) -> Result<CpuUsage> {
+            if setup.version == CgroupVersion::V1 {
+                let usage_user_ns: f64 = read_cgroup_file(dir.join("cpuacct.usage_user"))?.trim_end().parse()?;
+                let usage_sys_ns:  f64 = read_cgroup_file(dir.join("cpuacct.usage_sys" ))?.trim_end().parse()?;
+
+                // Throttling is in the cpu controller's cpu.stat, which is a different directory if cpu and cpuacct
+                // aren't mounted together. Swap the controller directory, the rest of the path is the same.
+                let root = &cfg().cgroupfs_dir;
+                let mut cgroup_path = dir.strip_prefix(root)?.components();
+                cgroup_path.next();
+                let cpu_controller = setup.cpu_dir.strip_prefix(root)?.components().next()
+                    .ok_or(Error::msg("cpu controller directory isn't in the cgroupfs"))?;
+                let cpu_dir = root.join(cpu_controller).join(cgroup_path.as_path());
+                let mut nr_throttled: Option<u64> = None;
+                let mut throttled_ns: Option<f64> = None;
+                for line in read_optional_cgroup_file(cpu_dir.join("cpu.stat"))?.unwrap_or_default().lines() {
+                    if line.starts_with("nr_throttled") {
+                        nr_throttled = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split nr_throttled line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("throttled_time") {
+                        throttled_ns = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split throttled_time line in cpu.stat"))?.parse()?);
+                    }
+                }
+                let percpu_sec = if cfg().percpu {
+                    let percpu_ns = read_cgroup_file(dir.join("cpuacct.usage_percpu"))?.split_ascii_whitespace()
+                        .map(str::parse::<f64>).collect::<Result<Vec<f64>, _>>()?;
+                    Some(percpu_ns.into_iter().map(|ns| ns / 1_000_000_000.0).collect())
+                } else { None };
+                Ok(CpuUsage {
+                    user_sec: usage_user_ns / 1_000_000_000.0,
+                    sys_sec: usage_sys_ns / 1_000_000_000.0,
+                    burst: None,
+                    burst_limit_us: None,
+                    throttled: nr_throttled.zip(throttled_ns).map(|(n, ns)| (n, ns / 1_000_000_000.0)),
+                    percpu_sec
+                })
+            } else {
+                let cpu_stat_file = dir.join("cpu.stat");
+                let cpu_stat = read_cgroup_file(&cpu_stat_file)?;
+                let mut user_us: Option<f64> = None;
+                let mut sys_us: Option<f64> = None;
+                let mut nr_bursts: Option<u64> = None;
+                let mut burst_us: Option<f64> = None;
+                let mut nr_throttled: Option<u64> = None;
+                let mut throttled_us: Option<f64> = None;
+                for line in cpu_stat.lines() {
+                    if line.starts_with("user_usec") {
+                        user_us = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split user_usec line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("system_usec") {
+                        sys_us = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split system_usec line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("nr_bursts") {
+                        nr_bursts = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split nr_bursts line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("burst_usec") {
+                        burst_us = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split burst_usec line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("nr_throttled") {
+                        nr_throttled = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split nr_throttled line in cpu.stat"))?.parse()?);
+                    } else if line.starts_with("throttled_usec") {
+                        throttled_us = Some(line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split throttled_usec line in cpu.stat"))?.parse()?);
+                    }
+                }
+                let burst_limit_us = match read_cgroup_file(dir.join("cpu.max.burst")) {
+                    Ok(burst) => parse_cgroup_limit(&burst)?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into())
+                };
+                if let (Some(user_us), Some(sys_us)) = (user_us, sys_us) {
+                    Ok(CpuUsage {
+                        user_sec: user_us / 1_000_000.0,
+                        sys_sec: sys_us / 1_000_000.0,
+                        burst: nr_bursts.zip(burst_us).map(|(n, us)| (n, us / 1_000_000.0)),
+                        burst_limit_us,
+                        throttled: nr_throttled.zip(throttled_us).map(|(n, us)| (n, us / 1_000_000.0)),
+                        percpu_sec: None
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Couldn't find one of user_usec or system_usec in {cpu_stat_file:?}"))
+                }
+            }
+        }
+
+        match get_metrics(setup, &cgroup.path) {
+            Ok(usage) => {
+                render_and_append_instance(&mut metric_user, scale_cpu(usage.user_sec), &cgroup);
+                render_and_append_instance(&mut metric_sys,  scale_cpu(usage.sys_sec),  &cgroup);
+                if let Some((nr_bursts, burst_sec)) = usage.burst {
+                    render_and_append_instance(&mut metric_burst_periods, nr_bursts, &cgroup);
+                    render_and_append_instance(&mut metric_burst, scale_cpu(burst_sec), &cgroup);
+                }
+                if let Some(burst_limit_us) = usage.burst_limit_us {
+                    render_and_append_instance(&mut metric_burst_limit, burst_limit_us, &cgroup);
+                }
+                if let Some((nr_throttled, throttled_sec)) = usage.throttled {
+                    render_and_append_instance(&mut metric_throttled_periods, nr_throttled, &cgroup);
+                    render_and_append_instance(&mut metric_throttled, scale_cpu(throttled_sec), &cgroup);
+                }
+                for (cpu, sec) in usage.percpu_sec.unwrap_or_default().into_iter().enumerate() {
+                    render_and_append_instance_with_labels(&mut metric_percpu, scale_cpu(sec), &cgroup, &[("cpu", &cpu.to_string())]);
+                }
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_user.render() + "\n";
+    out += &metric_sys.render();
+    out += "\n";
+    out += &metric_burst_periods.render();
+    out += "\n";
+    out += &metric_burst.render();
+    out += "\n";
+    out += &metric_throttled_periods.render();
+    out += "\n";
+    out += &metric_throttled.render();
+    out += "\n";
+    out += &metric_burst_limit.render();
+    out += "\n";
+    if cfg().percpu {
+        out += &metric_percpu.render();
+        out += "\n";
+    }
+    Ok(out)
+}
+
+fn get_blkio_metrics(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_read = PrometheusMetric::build()
+        .with_name("container_blkio_read_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Bytes read from disk by the container")
+        .build();
+
+    let mut metric_write = PrometheusMetric::build()
+        .with_name("container_blkio_write_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Bytes written to disk by the container")
+        .build();
+
+    let mut metric_read_ops = PrometheusMetric::build()
+        .with_name("container_blkio_read_ops_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Read operations done on disk by the container")
+        .build();
+
+    let mut metric_write_ops = PrometheusMetric::build()
+        .with_name("container_blkio_write_ops_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Write operations done on disk by the container")
+        .build();
+
+    let mut metric_latency_target = PrometheusMetric::build()
+        .with_name("container_spec_io_latency_target_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("I/O latency target (io.latency) of the container for the device in the device label")
+        .build();
+
+    let mut metric_weight = PrometheusMetric::build()
+        .with_name("container_spec_io_weight")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Proportional I/O weight (io.weight) of the container, per device or \"default\"")
+        .build();
+
+    /// Reads and writes (bytes or operations) per device, by major:minor
+    type DeviceTotals = BTreeMap<String, (u64, u64)>;
+
+    struct IoUsage {
+        bytes: DeviceTotals,
+        /// Read and write operations, if we have them
+        ops: Option<DeviceTotals>
+    }
+
+    let mut errors = ScrapeErrors::new("blkio");
+    for cgroup in container_cgroups(setup, &setup.blkio_dir) {
+        fn get_metrics(setup: &CgroupSetup, dir: &Path) -> Result<IoUsage> {
+            if setup.version == CgroupVersion::V1 {
+                let bytes = read_blkio_throttle_totals(dir, "blkio.throttle.io_service_bytes")?;
+                let ops = match read_blkio_throttle_totals(dir, "blkio.throttle.io_serviced") {
+                    Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => None,
+                    result => Some(result?)
+                };
+                return Ok(IoUsage { bytes, ops });
+            }
+
+            // No need to do anything for --blkio-recursive-sum here, io.stat already includes descendants on v2
+            let mut bytes = DeviceTotals::new();
+            let mut ops = DeviceTotals::new();
+            let io_stat = read_cgroup_file(dir.join("io.stat"))?;
+            for line in io_stat.lines() {
+                let mut fields = line.split_ascii_whitespace();
+                let Some(device) = fields.next() else { continue };
+                let (device_bytes, device_ops) = (bytes.entry(device.to_owned()).or_default(), ops.entry(device.to_owned()).or_default());
+                for kv in fields {
+                    if kv.contains('=') {
+                        let mut spl = kv.split('=');
+                        let first = spl.next().ok_or(Error::msg("Couldn't split kv pair in io.stat"))?;
+                        let last: u64 = spl.next_back().ok_or(Error::msg("Couldn't split kv pair in io.stat"))?.parse()?;
+                        match first {
+                            "rbytes" => device_bytes.0 += last,
+                            "wbytes" => device_bytes.1 += last,
+                            "rios" => device_ops.0 += last,
+                            "wios" => device_ops.1 += last,
+                            _ => ()
+                        }
+                    }
+                }
+            }
+            Ok(IoUsage { bytes, ops: Some(ops) })
+        }
+
+        /// Reads the per-device read and write totals from a v1 blkio.throttle file, or its _recursive version (or
+        /// the sum over the subtree) with --blkio-recursive-sum.
+        fn read_blkio_throttle_totals(dir: &Path, file_name: &str) -> Result<DeviceTotals> {
+            if !cfg().blkio_recursive_sum { return read_blkio_throttle_file(&dir.join(file_name)) }
+            match read_blkio_throttle_file(&dir.join(format!("{file_name}_recursive"))) {
+                Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {
+                    // Older kernels don't have the _recursive files, so add up the subtree ourselves
+                    let mut totals = read_blkio_throttle_file(&dir.join(file_name))?;
+                    add_descendant_blkio_throttle(dir, file_name, MAX_BLKIO_RECURSION_DEPTH, &mut totals);
+                    Ok(totals)
+                }
+                result => result
+            }
+        }
+
+        /// Reads a v1 blkio.throttle.io_service_bytes or blkio.throttle.io_serviced(_recursive) file. Its lines look
+        /// like "8:0 Read 4096", plus a Total line at the end.
+        fn read_blkio_throttle_file(file: &Path) -> Result<DeviceTotals> {
+            let mut totals = DeviceTotals::new();
+            let contents = read_cgroup_file(file)?;
+            for line in contents.lines() {
+                let mut fields = line.split_ascii_whitespace();
+                let (Some(device), Some(op), Some(value)) = (fields.next(), fields.next(), fields.next()) else { continue };
+                match op {
+                    "Read" => totals.entry(device.to_owned()).or_default().0 += value.parse::<u64>()?,
+                    "Write" => totals.entry(device.to_owned()).or_default().1 += value.parse::<u64>()?,
+                    _ => ()
+                }
+            }
+            Ok(totals)
+        }
+
+        /// Adds the I/O of every cgroup below `dir` (down to `depth` levels) to the totals. Cgroups can vanish while
+        /// we're walking them, so anything unreadable is just skipped.
+        fn add_descendant_blkio_throttle(dir: &Path, file_name: &str, depth: u8, totals: &mut DeviceTotals) {
+            if depth == 0 { return }
+            let Ok(subdirs) = fs::read_dir(dir) else { return };
+            for subdir in subdirs.filter_map(Result::ok) {
+                if !subdir.file_type().is_ok_and(|t| t.is_dir()) { continue }
+                match read_blkio_throttle_file(&subdir.path().join(file_name)) {
+                    Ok(subtotals) => for (device, (read, write)) in subtotals {
+                        let total = totals.entry(device).or_default();
+                        total.0 += read;
+                        total.1 += write;
+                    },
+                    Err(e) => { trace!("Skipping {:?}: {e}", subdir.path()); continue }
+                }
+                add_descendant_blkio_throttle(&subdir.path(), file_name, depth - 1, totals);
+            }
+        }
+
+        /// Renders the totals for each device with --blkio-per-device, otherwise summed up over all devices.
+        fn render_device_totals(read_metric: &mut PrometheusMetric, write_metric: &mut PrometheusMetric, totals: &DeviceTotals, cgroup: &ContainerCgroup) {
+            if cfg().blkio_per_device {
+                for (device, (read, write)) in totals {
+                    let device = device_label(device);
+                    render_and_append_instance_with_labels(read_metric, *read, cgroup, &[("device", &device)]);
+                    render_and_append_instance_with_labels(write_metric, *write, cgroup, &[("device", &device)]);
+                }
+            } else {
+                render_and_append_instance(read_metric, totals.values().map(|t| t.0).sum::<u64>(), cgroup);
+                render_and_append_instance(write_metric, totals.values().map(|t| t.1).sum::<u64>(), cgroup);
+            }
+        }
+
+        match get_metrics(setup, &cgroup.path) {
+            Ok(usage) => {
+                render_device_totals(&mut metric_read, &mut metric_write, &usage.bytes, &cgroup);
+                if let Some(ops) = usage.ops {
+                    render_device_totals(&mut metric_read_ops, &mut metric_write_ops, &ops, &cgroup);
+                }
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+
+        // I/O QoS settings are v2 only, and the files are only there if the io.latency / io.cost controllers are
+        // enabled. (io.cost's qos and model parameters are set for the whole host in the root cgroup.)
+        if setup.version == CgroupVersion::V2 {
+            match read_io_qos(&cgroup.path) {
+                Ok((latency_targets, weights)) => {
+                    for (device, target_usec) in latency_targets {
+                        render_and_append_instance_with_labels(&mut metric_latency_target, target_usec as f64 / 1_000_000.0,
+                            &cgroup, &[("device", &device_label(&device))]);
+                    }
+                    for (device, weight) in weights {
+                        render_and_append_instance_with_labels(&mut metric_weight, weight, &cgroup, &[("device", &device_label(&device))]);
+                    }
+                }
+                Err(e) => errors.push(&cgroup, e)
+            }
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_read.render() + "\n";
+    out += &(metric_write.render() + "\n");
+    out += &(metric_read_ops.render() + "\n");
+    out += &(metric_write_ops.render() + "\n");
+    out += &(metric_latency_target.render() + "\n");
+    out += &metric_weight.render();
+    Ok(out + "\n")
+}
+
+fn get_network_metrics(setup: &CgroupSetup) -> Result<String> {
+    if cfg().disable_network_metrics { return Ok(String::new()) }
+
+    let mut metric_rx = PrometheusMetric::build()
+        .with_name("container_network_receive_bytes_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Bytes received by the container over all its network interfaces except lo")
+        .build();
+
+    let mut metric_tx = PrometheusMetric::build()
+        .with_name("container_network_transmit_bytes_total")
+        .with_metric_type(MetricType::Counter)
+        .with_help("Bytes sent by the container over all its network interfaces except lo")
+        .build();
+
+    let mut errors = ScrapeErrors::new("network");
+    for cgroup in container_cgroups(setup, &setup.memory_dir) {
+        // The cgroupfs knows nothing about networking, so this comes from the container's network namespace,
+        // through one of its processes. Host network containers would just show the host's numbers.
+        if lookup_container(&cgroup, |cont| cont.host_config.network_mode == "host").unwrap_or(false) { continue }
+        let Some(pid) = container_pid(&cgroup) else {
+            trace!("Couldn't find a process of container {}, skipping its network metrics.", cgroup.id);
+            continue;
+        };
+
+        fn get_metrics(pid: u32) -> Result<(u64, u64)> {
+            let mut total_rx: u64 = 0;
+            let mut total_tx: u64 = 0;
+            let net_dev = read_cgroup_file(cfg().procfs_dir.join(pid.to_string()).join("net/dev"))?;
+            // Two header lines, then "  eth0: <8 receive fields> <8 transmit fields>"
+            for line in net_dev.lines().skip(2) {
+                let (interface, stats) = line.split_once(':').ok_or(Error::msg("Couldn't split line in net/dev"))?;
+                if interface.trim() == "lo" { continue }
+                let stats: Vec<&str> = stats.split_ascii_whitespace().collect();
+                total_rx += stats.first().ok_or(Error::msg("Missing receive bytes in net/dev"))?.parse::<u64>()?;
+                total_tx += stats.get(8).ok_or(Error::msg("Missing transmit bytes in net/dev"))?.parse::<u64>()?;
+            }
+            Ok((total_rx, total_tx))
+        }
+
+        match get_metrics(pid) {
+            Ok((rx, tx)) => {
+                render_and_append_instance(&mut metric_rx, rx, &cgroup);
+                render_and_append_instance(&mut metric_tx, tx, &cgroup);
+            }
+            // The process might have exited since we looked it up
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => (),
+            Err(e) => errors.push(&cgroup, e)
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_rx.render() + "\n";
+    out += &metric_tx.render();
+    Ok(out + "\n")
+}
+
+fn get_sched_metrics(setup: &CgroupSetup) -> Result<String> {
+    if !cfg().enable_sched_metrics { return Ok(String::new()) }
+
+    let mut metric_runqueue = PrometheusMetric::build()
+        .with_name("container_cpu_runqueue_wait_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Time the container's current processes have spent waiting to get on a CPU")
+        .build();
+
+    let mut metric_iowait = PrometheusMetric::build()
+        .with_name("container_cpu_iowait_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Time the container's current processes have spent waiting on block I/O (needs delay accounting)")
+        .build();
+
+    let mut errors = ScrapeErrors::new("sched");
+    for cgroup in container_cgroups(setup, &setup.cpu_dir) {
+        fn get_metrics(dir: &Path) -> Result<(f64, f64)> {
+            let mut runqueue_ns: u64 = 0;
+            let mut iowait_ticks: u64 = 0;
+            // Processes (and their threads) come and go while we're reading, so anything that's gone is skipped
+            for pid in read_cgroup_file(dir.join("cgroup.procs"))?.lines() {
+                let Ok(tasks) = fs::read_dir(cfg().procfs_dir.join(pid.trim()).join("task")) else { continue };
+                for task in tasks.filter_map(Result::ok) {
+                    // <time on cpu> <time waiting on a runqueue> <timeslices>, in nanoseconds
+                    if let Ok(schedstat) = read_cgroup_file(task.path().join("schedstat")) {
+                        runqueue_ns += schedstat.split_ascii_whitespace().nth(1)
+                            .ok_or(Error::msg("Couldn't split schedstat"))?.parse::<u64>()?;
+                    }
+                    // The command in parentheses can contain anything, so count fields from the closing one.
+                    // delayacct_blkio_ticks is field 42, the 40th after it.
+                    if let Ok(stat) = read_cgroup_file(task.path().join("stat")) {
+                        let (_, fields) = stat.rsplit_once(')').ok_or(Error::msg("Couldn't find command in stat"))?;
+                        iowait_ticks += fields.split_ascii_whitespace().nth(39)
+                            .ok_or(Error::msg("Couldn't find delayacct_blkio_ticks in stat"))?.parse::<u64>()?;
+                    }
+                }
+            }
+            // Clock ticks are USER_HZ, which is 100 on every Linux architecture
+            Ok((runqueue_ns as f64 / 1_000_000_000.0, iowait_ticks as f64 / 100.0))
+        }
+
+        match get_metrics(&cgroup.path) {
+            Ok((runqueue_sec, iowait_sec)) => {
+                render_and_append_instance(&mut metric_runqueue, runqueue_sec, &cgroup);
+                render_and_append_instance(&mut metric_iowait, iowait_sec, &cgroup);
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_runqueue.render() + "\n";
+    out += &metric_iowait.render();
+    Ok(out + "\n")
+}
+
+fn get_freezer_metric(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_frozen = PrometheusMetric::build()
+        .with_name("container_frozen")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Whether the container's cgroup is frozen by the kernel (1) or not (0)")
+        .build();
+
+    let mut errors = ScrapeErrors::new("freezer");
+    for cgroup in container_cgroups(setup, &setup.freezer_dir) {
+        fn get_metrics(setup: &CgroupSetup, dir: &Path) -> Result<u8> {
+            if setup.version == CgroupVersion::V1 {
+                // THAWED, FREEZING or FROZEN. Only count it once the kernel has actually finished freezing.
+                let state = read_cgroup_file(dir.join("freezer.state"))?;
+                Ok((state.trim_end() == "FROZEN") as u8)
+            } else {
+                let events_file = dir.join("cgroup.events");
+                let events = read_cgroup_file(&events_file)?;
+                for line in events.lines() {
+                    if line.starts_with("frozen") {
+                        let frozen: u8 = line.split_ascii_whitespace().last()
+                            .ok_or(Error::msg("Couldn't split frozen line in cgroup.events"))?.parse()?;
+                        return Ok(frozen);
+                    }
+                }
+                Err(anyhow::anyhow!("Couldn't find frozen in {events_file:?}"))
+            }
+        }
+
+        match get_metrics(setup, &cgroup.path) {
+            Ok(frozen) => {
+                render_and_append_instance(&mut metric_frozen, frozen, &cgroup);
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+    }
+    errors.log_summary();
+
+    Ok(metric_frozen.render() + "\n")
+}
+
+fn get_cpuset_metrics(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_count = PrometheusMetric::build()
+        .with_name("container_spec_cpuset_cpus_count")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Number of CPUs the container is allowed to run on")
+        .build();
+
+    let mut metric_info = PrometheusMetric::build()
+        .with_name("container_cpuset_info")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("CPUs the container is allowed to run on, in the cpuset label")
+        .build();
+
+    let mut errors = ScrapeErrors::new("cpuset");
+    for cgroup in container_cgroups(setup, &setup.cpuset_dir) {
+        fn get_metrics(setup: &CgroupSetup, dir: &Path) -> Result<(usize, String)> {
+            let cpus = read_cgroup_file(dir.join(match setup.version {
+                CgroupVersion::V1 => "cpuset.effective_cpus",
+                CgroupVersion::V2 => "cpuset.cpus.effective"
+            }))?.trim_end().to_owned();
+            Ok((count_cpu_list(&cpus)?, cpus))
+        }
+
+        match get_metrics(setup, &cgroup.path) {
+            Ok((count, cpus)) => {
+                render_and_append_instance(&mut metric_count, count, &cgroup);
+                render_and_append_instance_with_labels(&mut metric_info, 1, &cgroup, &[("cpuset", &cpus)]);
+            }
+            Err(e) => errors.push(&cgroup, e)
+        }
+    }
+    errors.log_summary();
+
+    let mut out = metric_count.render() + "\n";
+    out += &metric_info.render();
+    Ok(out + "\n")
+}
+
+fn get_info_metric(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_info = PrometheusMetric::build()
+        .with_name("container_info")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Metadata about the container, in the labels")
+        .build();
+
+    let mut metric_root = PrometheusMetric::build()
+        .with_name("container_runs_as_root")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("1 if the container is configured to run as root (or doesn't set a user), 0 otherwise")
+        .build();
+
+    // Debugging aid for slow scrapes: deep cgroup hierarchies take longer to walk
+    let mut metric_depth = PrometheusMetric::build()
+        .with_name("container_cgroup_depth")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("How many directories deep the container's cgroup is in the cgroup hierarchy")
+        .build();
+
+    for cgroup in container_cgroups(setup, &setup.memory_dir) {
+        let restart_policy = lookup_container(&cgroup, |cont| match &*cont.host_config.restart_policy.name {
+            "" => "no".to_owned(),
+            name => name.to_owned()
+        });
+        let network_mode = lookup_container(&cgroup, |cont| match &*cont.host_config.network_mode {
+            "" => "default".to_owned(),
+            mode => mode.to_owned()
+        });
+        let user = lookup_container(&cgroup, |cont| cont.config.user.clone());
+        let runs_as_root = lookup_container(&cgroup, |cont| cont.config.runs_as_root());
+        let command = if cfg().cmd_label {
+            lookup_container(&cgroup, |cont| {
+                let mut command = cont.config.full_command();
+                if command.chars().count() > MAX_COMMAND_LABEL_LEN {
+                    command = command.chars().take(MAX_COMMAND_LABEL_LEN).collect::<String>() + "...";
+                }
+                command
+            })
+        } else { None };
+
+        let mut labels: Vec<(&str, &str)> = Vec::new();
+        if let Some(restart_policy) = &restart_policy { labels.push(("restart_policy", restart_policy)); }
+        if let Some(network_mode) = &network_mode { labels.push(("network_mode", network_mode)); }
+        if let Some(user) = &user { labels.push(("user", user)); }
+        if let Some(command) = &command { labels.push(("command", command)); }
+        render_and_append_instance_with_labels(&mut metric_info, 1, &cgroup, &labels);
+
+        if let Some(runs_as_root) = runs_as_root {
+            render_and_append_instance(&mut metric_root, runs_as_root as u8, &cgroup);
+        }
+
+        if cfg().enable_debug_endpoints {
+            if let Ok(relative) = cgroup.path.strip_prefix(&cfg().cgroupfs_dir) {
+                // On v1, the first component is the controller's directory, which isn't part of the hierarchy
+                let mut depth = relative.components().count();
+                if setup.version == CgroupVersion::V1 { depth = depth.saturating_sub(1); }
+                render_and_append_instance(&mut metric_depth, depth, &cgroup);
+            }
+        }
+    }
+
+    let mut out = metric_info.render() + "\n";
+    out += &(metric_root.render() + "\n");
+    out += &metric_depth.render();
+    Ok(out + "\n")
+}
+
+fn get_state_metrics(setup: &CgroupSetup) -> Result<String> {
+    let mut metric_running = PrometheusMetric::build()
+        .with_name("container_running")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("1 if the container is running, 0 if it's stopped")
+        .build();
+
+    let mut metric_start_time = PrometheusMetric::build()
+        .with_name("container_start_time_seconds")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("When the container was last started, in UNIX time")
+        .build();
+
+    let mut metric_restarts = PrometheusMetric::build()
+        .with_name("container_restart_count")
+        .with_metric_type(MetricType::Gauge)
+        .with_help("Number of times Docker has restarted the container because of its restart policy")
+        .build();
+
+    // Restart counts stick around in the metadata of long gone containers, only report them while there's a cgroup
+    let with_cgroup: HashSet<String> = container_cgroups(setup, &setup.memory_dir).into_iter().map(|cgroup| cgroup.id).collect();
+
+    // Stopped containers have no cgroup, so this goes by Docker's metadata instead of the cgroupfs.
+    // Podman's containers.json has none of this.
+    let containers = if cfg().runtime == Runtime::Docker { known_containers() } else { Vec::new() };
+    for cgroup in containers {
+        let Some((running, started_at, restarts)) = lookup_container(&cgroup,
+            |cont| (cont.state.running, cont.state.started_at_unix(), cont.restart_count)) else { continue };
+        render_and_append_instance(&mut metric_running, running as u8, &cgroup);
+        if let Some(started_at) = started_at {
+            render_and_append_instance(&mut metric_start_time, started_at, &cgroup);
+        }
+        if with_cgroup.contains(&cgroup.id) {
+            render_and_append_instance(&mut metric_restarts, restarts, &cgroup);
+        }
+    }
+
+    let mut out = metric_running.render() + "\n";
+    out += &(metric_start_time.render() + "\n");
+    out += &metric_restarts.render();
+    Ok(out + "\n")
+}
+
+/// Every container in Docker's metadata, running or not, that isn't filtered out. These have no cgroup directory
+/// to speak of, so their path is empty.
+fn known_containers() -> Vec<ContainerCgroup> {
+    let mut ids: Vec<String> = {
+        let mut map = crate::containers::CONTAINERS_MAP.lock().unwrap();
+        if map.is_empty() { refresh_containers_map(&mut map); }
+        map.keys().cloned().collect()
+    };
+    ids.sort();
+    ID_FILTER.with_borrow(|id_filter| if let Some(id) = id_filter { ids.retain(|cont_id| cont_id.starts_with(id.as_str())) });
+    ids.into_iter()
+        .filter(|id| should_include_container(id))
+        .map(|id| ContainerCgroup { id, path: PathBuf::new(), parent_id: None, pod_uid: None })
+        .collect()
+}
+
+/// Escapes backslashes, double quotes and newlines for use in a label value.
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Looks up the container's metadata (refreshing it if needed) and returns whatever `f` picks out of it.
+/// Returns None if there's no metadata, e.g. for nested containers.
+fn lookup_container<T>(cgroup: &ContainerCgroup, f: impl FnOnce(&ContainerDetails) -> T) -> Option<T> {
+    if !cgroup.has_docker_metadata() { return None }
+    let mut map = crate::containers::CONTAINERS_MAP.lock().unwrap();
+    if !map.contains_key(&cgroup.id) {
+        refresh_containers_map(&mut map);
+    }
+    map.get(&cgroup.id).map(f)
+}
+
+/// Finds a process in the container, as a PID in the host's /proc: the first one in the container's cgroup, or else
+/// the main process according to Docker (processes can sit in child cgroups, e.g. with systemd in the container).
+fn container_pid(cgroup: &ContainerCgroup) -> Option<u32> {
+    let from_cgroup = read_cgroup_file(cgroup.path.join("cgroup.procs")).ok()
+        .and_then(|procs| procs.lines().next().and_then(|pid| pid.trim().parse().ok()));
+    from_cgroup.or_else(|| lookup_container(cgroup, |cont| cont.state.pid).filter(|pid| *pid != 0))
+}
+
+/// Values by device ("MAJ:MIN").
+type PerDevice = Vec<(String, u64)>;
+
+/// Reads the io.latency targets (in microseconds) and io.weight weights of a v2 cgroup.
+fn read_io_qos(dir: &Path) -> Result<(PerDevice, PerDevice)> {
+    let mut latency_targets = Vec::new();
+    for line in read_optional_cgroup_file(dir.join("io.latency"))?.unwrap_or_default().lines() {
+        // 8:16 target=75000
+        let (device, settings) = line.split_once(' ').ok_or(Error::msg("Couldn't split line in io.latency"))?;
+        if let Some(target) = settings.split_ascii_whitespace().find_map(|kv| kv.strip_prefix("target=")) {
+            latency_targets.push((device.to_owned(), target.parse()?));
+        }
+    }
+
+    let mut weights = Vec::new();
+    for line in read_optional_cgroup_file(dir.join("io.weight"))?.unwrap_or_default().lines() {
+        // default 100, or 8:16 200
+        let (device, weight) = line.split_once(' ').ok_or(Error::msg("Couldn't split line in io.weight"))?;
+        weights.push((device.to_owned(), weight.trim().parse()?));
+    }
+    Ok((latency_targets, weights))
+}
+
+/// Parses a limit out of a cgroup file, None meaning unlimited. That's "max" on v2, and on v1 either -1 or a number
+/// somewhere just under i64::MAX (depending on page size), which would look like a real limit on a dashboard.
+fn parse_cgroup_limit(value: &str) -> Result<Option<u64>> {
+    match value.trim() {
+        "max" | "-1" => Ok(None),
+        value => match value.parse::<u64>()? {
+            limit if limit >= i64::MAX as u64 / 2 => Ok(None),
+            limit => Ok(Some(limit))
+        }
+    }
+}
+
+/// Like [read_cgroup_file], but a file that isn't there is None instead of an error. For files that only exist
+/// with some controllers or kernel features enabled.
+fn read_optional_cgroup_file<P: AsRef<Path>>(path: P) -> io::Result<Option<String>> {
+    match read_cgroup_file(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// What to put in a device label for a major:minor device number: the device's name (like sda) with
+/// --blkio-device-names, if we can find it in the sysfs, and the number otherwise. Both are cached.
+fn device_label(device: &str) -> String {
+    if !cfg().blkio_device_names { return device.to_owned() }
+    let mut names = DEVICE_NAMES.lock().unwrap();
+    if let Some(name) = names.get(device) { return name.clone() }
+
+    // /sys/dev/block/8:0 is a symlink to the device, like ../../devices/pci0000:00/.../block/sda
+    let link = cfg().sysfs_dir.join("dev/block").join(device);
+    match fs::read_link(&link).ok().and_then(|target| Some(target.file_name()?.to_string_lossy().into_owned())) {
+        Some(name) => {
+            trace!("Block device {device} is {name}.");
+            names.insert(device.to_owned(), name.clone());
+            name
+        }
+        None => {
+            trace!("Couldn't find a name for block device {device} at {link:?}.");
+            names.insert(device.to_owned(), device.to_owned());
+            device.to_owned()
+        }
+    }
+}
+
+/// Reads a cgroup stat file, retrying a few times (see --read-retries) if the kernel interrupts the read or
+/// asks us to try again. Interrupted reads are retried immediately, EAGAIN gets a tiny backoff.
+fn read_cgroup_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    // Keeps the kind, so callers can still tell a missing file apart
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{:?}: {e}", path.as_ref()));
+    let mut attempt = 0;
+    loop {
+        FILES_READ.set(FILES_READ.get() + 1);
+        match fs::read_to_string(path.as_ref()) {
+            Err(e) if attempt < cfg().read_retries => match e.kind() {
+                io::ErrorKind::Interrupted => trace!("Read of {:?} interrupted, retrying.", path.as_ref()),
+                io::ErrorKind::WouldBlock => {
+                    trace!("Read of {:?} would block, retrying.", path.as_ref());
+                    thread::sleep(Duration::from_millis(1 << attempt));
+                }
+                _ => return Err(with_path(e))
+            },
+            result => return result.map_err(with_path)
+        }
+        attempt += 1;
+    }
+}
+
+/// Collects one collector's per-container errors during a scrape, so they get logged as a single summary line
+/// instead of flooding the logs. The details are only logged at debug level.
+struct ScrapeErrors {
+    collector: &'static str,
+    counts: BTreeMap<String, usize>,
+    containers: HashSet<String>,
+    total: usize
+}
+
+impl ScrapeErrors {
+    fn new(collector: &'static str) -> Self {
+        ScrapeErrors { collector, counts: BTreeMap::new(), containers: HashSet::new(), total: 0 }
+    }
+
+    fn push(&mut self, cgroup: &ContainerCgroup, e: Error) {
+        debug!("Metrics parsing error ({}) for container {} in {:?}: {e}", self.collector, cgroup.id, cgroup.path);
+        let kind = if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
+            format!("{:?}", io_err.kind())
+        } else if e.is::<ParseIntError>() || e.is::<ParseFloatError>() {
+            "InvalidNumber".to_owned()
+        } else {
+            "Other".to_owned()
+        };
+        *self.counts.entry(kind).or_default() += 1;
+        self.containers.insert(cgroup.id.clone());
+        self.total += 1;
+        SCRAPE_ERROR_COUNT.set(SCRAPE_ERROR_COUNT.get() + 1);
+    }
+
+    fn log_summary(&self) {
+        if self.total == 0 { return }
+        let kinds: Vec<String> = self.counts.iter().map(|(kind, n)| format!("{kind}×{n}")).collect();
+        warn!("{} containers failed {} parse with {} errors: {}", self.containers.len(), self.collector, self.total, kinds.join(", "));
+    }
+}
+
+/// Counts the CPUs in a kernel cpu list such as "0-3,8".
+fn count_cpu_list(list: &str) -> Result<usize> {
+    let mut count = 0;
+    for range in list.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let (start, end): (usize, usize) = (start.parse()?, end.parse()?);
+                if end < start { return Err(anyhow::anyhow!("Backwards range {range:?} in cpu list")) }
+                count += end - start + 1;
+            }
+            None => { range.parse::<usize>()?; count += 1; }
+        }
+    }
+    Ok(count)
+}
+
+/// Appends one series for the container in `cgroup` to `metric`.
+///
+/// The `id` label always comes straight from the cgroup directory name, never from metadata, so every series is
+/// keyed on the container ID. A recreated container gets a new ID and therefore a fresh series; only a restart of
+/// the same container (same ID, new cgroup) shows up as a counter reset, which Prometheus' rate() handles.
+fn render_and_append_instance<N: num::Num + std::fmt::Display + core::fmt::Debug>(metric: &mut PrometheusMetric<'_>, value: N, cgroup: &ContainerCgroup) {
+    render_and_append_instance_with_labels(metric, value, cgroup, &[]);
+}
+
+/// The name of a metric family, for match[] selectors with a metric name. PrometheusMetric doesn't give it out, so
+/// this gets it from the family's # TYPE line the first time, and remembers it for the rest of the collector run.
+fn family_name(metric: &PrometheusMetric<'_>) -> String {
+    FAMILY_NAMES.with_borrow_mut(|names| names.entry(metric as *const _ as usize).or_insert_with(|| {
+        metric.render().lines().find_map(|l| Some(l.strip_prefix("# TYPE ")?.split(' ').next()?.to_owned())).unwrap_or_default()
+    }).clone())
+}
+
+/// Same as [render_and_append_instance], but with some extra metric-specific labels added after the `id`, `name`
+/// and `image` labels.
+fn render_and_append_instance_with_labels<N: num::Num + std::fmt::Display + core::fmt::Debug>(
+    metric: &mut PrometheusMetric<'_>, value: N, cgroup: &ContainerCgroup, extra_labels: &[(&str, &str)]
+) {
+    // Labels go in a fixed order: id, name and image first, then our other labels, then the ones made from Docker
+    // labels. Prometheus doesn't care, but people reading the output and golden-file tests do.
+    let cont_id = &*cgroup.id;
+    let mut other_labels: Vec<(&str, &str)> = Vec::new();
+    let mut docker_labels_out: Vec<(&str, &str)> = Vec::new();
+
+    // Pod UIDs aren't container IDs, those stay whole
+    let mut labels: Vec<(&str, &str)> = if cfg().short_id && is_container_id(cont_id) {
+        other_labels.push(("full_id", cont_id));
+        vec![("id", &cont_id[..SHORT_ID_LEN])]
+    } else {
+        vec![("id", cont_id)]
+    };
+
+    if let Some(node) = &cfg().node_name {
+        other_labels.push(("node", node));
+    }
+
+    if let Some(parent_id) = &cgroup.parent_id {
+        other_labels.push(("parent_id", parent_id));
+    }
+
+    if let Some(pod_uid) = &cgroup.pod_uid {
+        other_labels.push(("pod_uid", pod_uid));
+    }
+
+    other_labels.extend_from_slice(extra_labels);
+
+    // Debugging aid only (--debug-cgroup-path-label): one more label with the directory the value came from.
+    // Off by default, since it adds a lot of cardinality and just repeats the id.
+    let cgroup_path = cgroup.path.to_string_lossy();
+    if cfg().debug_cgroup_path_label && !cgroup_path.is_empty() {
+        other_labels.push(("cgroup_path", &cgroup_path));
+    }
+
+    let mut map;
+    let label_keys: append_only_vec::AppendOnlyVec<String> = append_only_vec::AppendOnlyVec::new();
+    let mut aggregate_value = "";
+
+    // Nested containers' metadata lives inside their parent container, out of our reach, and Kubernetes doesn't
+    // put its containers' metadata in --containers-dir either
+    if cgroup.has_docker_metadata() {
+        map = crate::containers::CONTAINERS_MAP.lock().unwrap();
+        if !map.contains_key(cont_id) {
+            refresh_containers_map(&mut map);
+        }
+
+        let include_labels = &cfg().include_labels_set;
+        let exclude_labels = &cfg().exclude_labels_set;
+
+        if let Some(cont) = map.get(cont_id) {
+            labels.push(("name", &cont.name));
+            labels.push(("image", &cont.config.image));
+            aggregate_value = match &cfg().aggregate_labels {
+                Some((_, Some(docker_label))) => cont.config.labels.get(docker_label).map_or("", String::as_str),
+                _ => &cont.config.image
+            };
+
+            let mut docker_labels: Vec<(String, &str, &str)> = Vec::new();
+            for (label_key, label_val) in &cont.config.labels {
+                trace!("Inserting label {} ...", label_key);
+                if !include_labels.is_empty() {
+                    if !include_labels.contains(label_key) { trace!("Not included."); continue; }
+                } else if !exclude_labels.is_empty() && exclude_labels.contains(label_key) {
+                    trace!("Excluded.");
+                    continue;
+                }
+                let sanitized: String = label_key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+                let key = format!("{}{sanitized}", cfg().label_prefix);
+                // With a short or empty --label-prefix, a Docker label could also clash with one of our own labels
+                if !is_label_name(&key) || labels.iter().chain(&other_labels).any(|(name, _)| *name == key) {
+                    if WARNED_INVALID_LABELS.lock().unwrap().insert(label_key.clone()) {
+                        warn!("Container label {label_key:?} doesn't make a usable metric label name ({key:?}), skipping it.");
+                    }
+                    continue;
+                }
+                docker_labels.push((key, label_key, label_val));
+            }
+
+            // Docker labels like app.name and app-name end up with the same metric label name, and a series can't
+            // have the same label twice. Keep whichever Docker label sorts first so it's the same one every scrape.
+            let mut winners: HashMap<String, &str> = HashMap::new();
+            for (key, label_key, _) in &docker_labels {
+                let winner = winners.entry(key.clone()).or_insert(label_key);
+                if *winner != *label_key {
+                    let (keep, drop) = if *label_key < *winner { (*label_key, *winner) } else { (*winner, *label_key) };
+                    if WARNED_LABEL_COLLISIONS.lock().unwrap().insert((keep.to_owned(), drop.to_owned())) {
+                        warn!("Container labels {keep:?} and {drop:?} both become metric label {key}, only using {keep:?}.");
+                    }
+                    *winner = keep;
+                }
+            }
+
+            for (key, label_key, label_val) in docker_labels {
+                if winners[&key] != label_key { continue }
+                let idx = label_keys.push(key);
+                docker_labels_out.push((&label_keys[idx], label_val));
+            }
+        } else {
+            warn!("Couldn't find details for container ID {cont_id}");
+        }
+    }
+
+    labels.append(&mut other_labels);
+    docker_labels_out.sort_by_key(|(key, _)| *key);
+    labels.append(&mut docker_labels_out);
+
+    if cfg().deterministic_labels {
+        labels.sort_by_key(|(key, _)| *key);
+    }
+
+    let selected = SELECTORS.with_borrow(|selectors| {
+        // Only worth looking up if there's something to match it against
+        let family = if selectors.iter().any(Selector::has_name_matchers) { family_name(metric) } else { String::new() };
+        selectors.is_empty() || selectors.iter().any(|s| s.matches(&family, &labels))
+    });
+    if !selected { return }
+
+    // With --aggregate-by, every container of a group gets the same series, and aggregate_duplicate_series combines them
+    if let Some((aggregate_label, _)) = &cfg().aggregate_labels {
+        labels = vec![(aggregate_label, aggregate_value)];
+        labels.extend(cfg().node_name.as_deref().map(|node| ("node", node)));
+        labels.extend_from_slice(extra_labels);
+    }
+
+    // prometheus_exporter_base writes label values as they are, and any of them can come from a Docker label
+    let labels: Vec<(&str, String)> = labels.into_iter().map(|(key, val)| (key, escape_label_value(val))).collect();
+    let mut prom = PrometheusInstance::new()
+        .with_value(value)
+        .with_current_timestamp()
+        .expect("error getting UNIX time for timestamp");
+    for (key, val) in &labels {
+        prom = prom.with_label(*key, val.as_str());
+    }
+    metric.render_and_append_instance(&prom);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::init_test_cfg;
+    use crate::containers::CONTAINERS_MAP;
+
+    /// Puts a container into the metadata map, like reading its config.v2.json would.
+    fn add_container(id: &str, name: &str, labels: &[(&str, &str)]) {
+        let labels: HashMap<&str, &str> = labels.iter().copied().collect();
+        let config = serde_json::json!({ "ID": id, "Name": format!("/{name}"), "Config": { "Image": "nginx", "Labels": labels } });
+        CONTAINERS_MAP.lock().unwrap().insert(id.to_owned(), serde_json::from_value(config).unwrap());
+    }
+
+    fn cgroup(id: &str) -> ContainerCgroup {
+        ContainerCgroup { id: id.to_owned(), path: PathBuf::from("/sys/fs/cgroup/docker").join(id), parent_id: None, pod_uid: None }
+    }
+
+    fn test_metric() -> PrometheusMetric<'static> {
+        PrometheusMetric::build().with_name("test_metric").with_metric_type(MetricType::Gauge).with_help("Test").build()
+    }
+
+    fn samples(output: &str) -> Vec<&str> {
+        output.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).collect()
+    }
+
+    #[test]
+    fn recreated_container_gets_a_new_series() {
+        init_test_cfg();
+        let (old_id, new_id) = ("1".repeat(64), "2".repeat(64));
+        add_container(&old_id, "web", &[]);
+        add_container(&new_id, "web", &[]);
+
+        let mut metric = test_metric();
+        render_and_append_instance(&mut metric, 5, &cgroup(&old_id));
+        render_and_append_instance(&mut metric, 1, &cgroup(&new_id));
+        let output = metric.render();
+        let samples = samples(&output);
+
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].starts_with(&format!("test_metric{{id=\"{old_id}\",name=\"web\"")));
+        assert!(samples[1].starts_with(&format!("test_metric{{id=\"{new_id}\",name=\"web\"")));
+    }
+
+    #[test]
+    fn wildcards_match_any_characters() {
+        assert!(wildcard_match("*", "kubepods-burstable.slice"));
+        assert!(wildcard_match("kubepods-*-pod*.slice", "kubepods-burstable-pod1.slice"));
+        assert!(wildcard_match("docker", "docker"));
+        assert!(!wildcard_match("docker", "dockerd"));
+        assert!(!wildcard_match("kubepods-*.slice", "kubepods.slice"));
+        assert!(!wildcard_match("*-pod*.slice", "kubepods-burstable.slice"));
+    }
+
+    #[test]
+    fn colliding_docker_labels_keep_the_first_key() {
+        init_test_cfg();
+        let id = "3".repeat(64);
+        // Both become container_label_app_name, and "app-name" sorts before "app.name"
+        add_container(&id, "app", &[("app.name", "dotted"), ("app-name", "dashed")]);
+
+        let mut metric = test_metric();
+        render_and_append_instance(&mut metric, 1, &cgroup(&id));
+        let output = metric.render();
+        let samples = samples(&output);
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].matches("container_label_app_name=").count(), 1);
+        assert!(samples[0].contains("container_label_app_name=\"dashed\""));
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(count_cpu_list("0-3,8").unwrap(), 5);
+        assert_eq!(count_cpu_list("0").unwrap(), 1);
+        assert_eq!(count_cpu_list("0-1,4,6-7\n").unwrap(), 5);
+        // An empty cpuset (no CPUs yet) is an empty file, or just a newline
+        assert_eq!(count_cpu_list("").unwrap(), 0);
+        assert_eq!(count_cpu_list("\n").unwrap(), 0);
+        assert!(count_cpu_list("3-1").is_err());
+        assert!(count_cpu_list("0-3,a").is_err());
+        assert!(count_cpu_list("0-x").is_err());
+        assert!(count_cpu_list("-1").is_err());
+    }
+
+    #[test]
+    fn unlimited_cgroup_limits() {
+        assert_eq!(parse_cgroup_limit("max").unwrap(), None);
+        assert_eq!(parse_cgroup_limit("max\n").unwrap(), None);
+        assert_eq!(parse_cgroup_limit("-1").unwrap(), None);
+        // v1's "unlimited" with 4k pages
+        assert_eq!(parse_cgroup_limit("9223372036854771712\n").unwrap(), None);
+        assert_eq!(parse_cgroup_limit(&(i64::MAX as u64 / 2).to_string()).unwrap(), None);
+        assert_eq!(parse_cgroup_limit(&i64::MAX.to_string()).unwrap(), None);
+        assert_eq!(parse_cgroup_limit(&u64::MAX.to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn real_cgroup_limits() {
+        assert_eq!(parse_cgroup_limit("536870912\n").unwrap(), Some(536870912));
+        assert_eq!(parse_cgroup_limit("0").unwrap(), Some(0));
+        assert_eq!(parse_cgroup_limit(&(i64::MAX as u64 / 2 - 1).to_string()).unwrap(), Some(i64::MAX as u64 / 2 - 1));
+        assert!(parse_cgroup_limit("unlimited").is_err());
+        assert!(parse_cgroup_limit("-2").is_err());
+    }
+
+    #[test]
+    fn aggregation_only_adds_up_what_adds_up() {
+        let output = "# HELP container_running Running\n# TYPE container_running gauge\n\
+            container_running{image=\"nginx\"} 1 1000\ncontainer_running{image=\"nginx\"} 1 1001\n\n\
+            # HELP container_start_time_seconds Started\n# TYPE container_start_time_seconds gauge\n\
+            container_start_time_seconds{image=\"nginx\"} 1700000000 1000\ncontainer_start_time_seconds{image=\"nginx\"} 1600000000 1001\n\n\
+            # HELP container_memory_usage Memory\n# TYPE container_memory_usage gauge\n\
+            container_memory_usage{image=\"nginx\"} 100 1000\ncontainer_memory_usage{image=\"nginx\"} 50 1001\n\n\
+            # HELP container_cpu_user_total CPU\n# TYPE container_cpu_user_total counter\n\
+            container_cpu_user_total{image=\"nginx\"} 2.5 1000\ncontainer_cpu_user_total{image=\"nginx\"} 1.5 1001\n\n";
+        let aggregated = aggregate_duplicate_series(output);
+        assert_eq!(samples(&aggregated), [
+            "container_running{image=\"nginx\"} 1 1000",
+            "container_start_time_seconds{image=\"nginx\"} 1600000000 1000",
+            "container_memory_usage{image=\"nginx\"} 150 1000",
+            "container_cpu_user_total{image=\"nginx\"} 4 1000"
+        ]);
+        assert_eq!(aggregation_for("container_memory_limit_bytes", "gauge"), Aggregation::Max);
+        assert_eq!(aggregation_for("container_spec_cpuset_cpus_count", "gauge"), Aggregation::Max);
+        assert_eq!(aggregation_for("container_memory_cache_mib", "gauge"), Aggregation::Sum);
+    }
+}
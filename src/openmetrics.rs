@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+
+/// Content type of the OpenMetrics text format, for clients that ask for it in their Accept header.
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Units OpenMetrics can declare with # UNIT, when a family's name ends in one.
+const UNITS: &[&str] = &["seconds", "bytes"];
+
+/// Whether an Accept header asks for OpenMetrics. Prometheus sends this (with plain text as a fallback) when it's
+/// configured to prefer OpenMetrics.
+pub fn accepts_openmetrics(accept: &str) -> bool {
+    accept.split(',').any(|media_type| media_type.trim().starts_with("application/openmetrics-text"))
+}
+
+/// Turns our Prometheus text format output into OpenMetrics text format.
+///
+/// The differences that matter for us: counter families are named without the _total their samples have,
+/// timestamps are in seconds instead of milliseconds, there are no empty lines, and it ends with # EOF.
+pub fn to_openmetrics(output: &str) -> String {
+    let mut out = String::with_capacity(output.len() + 16);
+    let counters: HashSet<&str> = output.lines()
+        .filter_map(|line| line.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+        .collect();
+
+    for line in output.lines() {
+        if line.is_empty() { continue }
+
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("TYPE"), Some(name), Some(metric_type)) => {
+                    let family = family_name(&counters, name);
+                    let metric_type = if metric_type == "untyped" { "unknown" } else { metric_type };
+                    out += &format!("# TYPE {family} {metric_type}\n");
+                    if let Some(unit) = UNITS.iter().find(|unit| family.ends_with(&format!("_{unit}"))) {
+                        out += &format!("# UNIT {family} {unit}\n");
+                    }
+                }
+                (Some("HELP"), Some(name), help) => out += &format!("# HELP {} {}\n", family_name(&counters, name), help.unwrap_or_default()),
+                _ => () // other comments aren't allowed in OpenMetrics
+            }
+            continue;
+        }
+
+        let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+        let name = &line[..name_end];
+        let (series, timestamp) = split_timestamp(line, name_end);
+        if counters.contains(name) {
+            // Counter samples always end in _total, even if the family name didn't
+            out += &format!("{}_total{}", family_name(&counters, name), &series[name_end..]);
+        } else {
+            out += series;
+        }
+        if let Some(timestamp_ms) = timestamp {
+            out += &format!(" {}.{:03}", timestamp_ms.div_euclid(1000), timestamp_ms.rem_euclid(1000));
+        }
+        out += "\n";
+    }
+    out += "# EOF\n";
+    out
+}
+
+/// Counter families are named without the _total at the end.
+fn family_name<'a>(counters: &HashSet<&str>, name: &'a str) -> &'a str {
+    if counters.contains(name) { name.strip_suffix("_total").unwrap_or(name) } else { name }
+}
+
+/// Splits a sample line into everything up to the value, and the timestamp in milliseconds if there is one.
+fn split_timestamp(line: &str, name_end: usize) -> (&str, Option<i64>) {
+    // Label values can have spaces in them, but the value and timestamp come after the last }
+    let after_labels = line.rfind('}').map_or(name_end, |i| i + 1);
+    let fields: Vec<&str> = line[after_labels..].split_ascii_whitespace().collect();
+    match fields[..] {
+        [_, timestamp] => match (timestamp.parse(), line.rfind(' ')) {
+            (Ok(timestamp_ms), Some(space)) => (&line[..space], Some(timestamp_ms)),
+            _ => (line, None)
+        },
+        _ => (line, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_lose_total_in_the_family_name_only() {
+        let output = to_openmetrics("# HELP container_oom_kills_total OOM kills\n# TYPE container_oom_kills_total counter\n\
+            container_oom_kills_total{id=\"a\"} 3 1700000000123\n\n");
+        assert_eq!(output, "# HELP container_oom_kills OOM kills\n# TYPE container_oom_kills counter\n\
+            container_oom_kills_total{id=\"a\"} 3 1700000000.123\n# EOF\n");
+    }
+
+    #[test]
+    fn units_only_for_seconds_and_bytes() {
+        let output = to_openmetrics("# TYPE container_cpu_throttled_seconds_total counter\n\
+            # TYPE container_memory_limit_bytes gauge\n# TYPE container_memory_limit_mib gauge\n\
+            # TYPE container_processes gauge\n# TYPE dockerprom_build_info untyped\n");
+        assert_eq!(output, "# TYPE container_cpu_throttled_seconds counter\n# UNIT container_cpu_throttled_seconds seconds\n\
+            # TYPE container_memory_limit_bytes gauge\n# UNIT container_memory_limit_bytes bytes\n\
+            # TYPE container_memory_limit_mib gauge\n# TYPE container_processes gauge\n\
+            # TYPE dockerprom_build_info unknown\n# EOF\n");
+    }
+
+    #[test]
+    fn timestamps_become_seconds() {
+        assert_eq!(split_timestamp("up 1 1700000000005", 2), ("up 1", Some(1700000000005)));
+        let output = to_openmetrics("# TYPE up gauge\nup 1 1700000000005\nup{id=\"b\"} 0 999\n");
+        assert_eq!(output, "# TYPE up gauge\nup 1 1700000000.005\nup{id=\"b\"} 0 0.999\n# EOF\n");
+    }
+
+    #[test]
+    fn samples_without_timestamps() {
+        // --raw-counters drops the counters' timestamps
+        assert_eq!(split_timestamp("up{id=\"a\"} 1", 2), ("up{id=\"a\"} 1", None));
+        let output = to_openmetrics("# TYPE container_cpu_user_total counter\ncontainer_cpu_user_total{id=\"a\"} 1.5\n");
+        assert_eq!(output, "# TYPE container_cpu_user counter\ncontainer_cpu_user_total{id=\"a\"} 1.5\n# EOF\n");
+    }
+
+    #[test]
+    fn label_values_with_braces_and_spaces() {
+        let line = "container_info{cmd=\"sh -c {a} 5 6\",id=\"a\"} 1 1700000000000";
+        assert_eq!(split_timestamp(line, 14), ("container_info{cmd=\"sh -c {a} 5 6\",id=\"a\"} 1", Some(1700000000000)));
+        let line = "container_info{cmd=\"echo }\"} 1";
+        assert_eq!(split_timestamp(line, 14), (line, None));
+    }
+
+    #[test]
+    fn blank_lines_dropped_and_one_eof() {
+        let output = to_openmetrics("# TYPE a gauge\na 1\n\n# TYPE b gauge\nb 2\n\n\n");
+        assert!(!output.lines().any(str::is_empty));
+        assert_eq!(output.matches("# EOF").count(), 1);
+        assert!(output.ends_with("b 2\n# EOF\n"));
+        assert_eq!(to_openmetrics(""), "# EOF\n");
+    }
+
+    #[test]
+    fn accept_header() {
+        assert!(accepts_openmetrics("application/openmetrics-text; version=1.0.0; charset=utf-8,text/plain;version=0.0.4;q=0.5"));
+        assert!(accepts_openmetrics("text/plain;q=0.5, application/openmetrics-text;version=0.0.1"));
+        assert!(!accepts_openmetrics("text/plain; version=0.0.4"));
+        assert!(!accepts_openmetrics("*/*"));
+        assert!(!accepts_openmetrics(""));
+    }
+}
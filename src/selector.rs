@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+/// A Prometheus series selector from a match[] query parameter, like `{image="nginx",name!~"/test-.*"}` or
+/// `container_memory_usage{name="/web"}`.
+#[derive(Debug)]
+pub struct Selector(Vec<Matcher>);
+
+#[derive(Debug)]
+enum Matcher {
+    Equal(String, String),
+    NotEqual(String, String),
+    Regex(String, Regex),
+    NotRegex(String, Regex)
+}
+
+impl Matcher {
+    fn label(&self) -> &str {
+        match self { Matcher::Equal(name, _) | Matcher::NotEqual(name, _) | Matcher::Regex(name, _) | Matcher::NotRegex(name, _) => name }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Equal(_, expected) => value == expected,
+            Matcher::NotEqual(_, expected) => value != expected,
+            Matcher::Regex(_, regex) => regex.is_match(value),
+            Matcher::NotRegex(_, regex) => !regex.is_match(value)
+        }
+    }
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector> {
+        let mut s = selector.trim();
+        let mut matchers = Vec::new();
+
+        // A metric name in front is short for {__name__="..."}, and can be all there is
+        let name_end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':')).unwrap_or(s.len());
+        if name_end > 0 {
+            matchers.push(Matcher::Equal("__name__".to_owned(), s[..name_end].to_owned()));
+            s = s[name_end..].trim_start();
+            if s.is_empty() { return Ok(Selector(matchers)) }
+        }
+        s = s.strip_prefix('{')
+            .ok_or(anyhow!("selector {selector:?} must be a metric name and/or label matchers in braces"))?;
+
+        loop {
+            s = s.trim_start();
+            if let Some(rest) = s.strip_prefix('}') {
+                if !rest.trim().is_empty() { return Err(anyhow!("trailing garbage after selector {selector:?}")) }
+                return Ok(Selector(matchers))
+            }
+
+            let name_end = s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len());
+            let name = &s[..name_end];
+            if name.is_empty() { return Err(anyhow!("expected a label name in selector {selector:?}")) }
+            s = s[name_end..].trim_start();
+
+            let op = ["!=", "=~", "!~", "="].into_iter().find(|op| s.starts_with(op))
+                .ok_or(anyhow!("expected =, !=, =~ or !~ after label {name} in selector {selector:?}"))?;
+            let (value, rest) = parse_quoted(s[op.len()..].trim_start())
+                .ok_or(anyhow!("expected a quoted value for label {name} in selector {selector:?}"))?;
+            let name = name.to_owned();
+            matchers.push(match op {
+                "=" => Matcher::Equal(name, value),
+                "!=" => Matcher::NotEqual(name, value),
+                // Prometheus regex matchers are fully anchored
+                "=~" => Matcher::Regex(name, Regex::new(&format!("^(?:{value})$"))?),
+                _ => Matcher::NotRegex(name, Regex::new(&format!("^(?:{value})$"))?)
+            });
+
+            s = rest.trim_start();
+            if let Some(rest) = s.strip_prefix(',') { s = rest }
+            else if !s.starts_with('}') { return Err(anyhow!("expected , or }} in selector {selector:?}")) }
+        }
+    }
+
+    /// Whether a series of the metric family `family` with these labels is selected. Labels that aren't there count
+    /// as empty, like in Prometheus. The family name is what `__name__` (or a metric name in front) is matched
+    /// against.
+    pub fn matches(&self, family: &str, labels: &[(&str, &str)]) -> bool {
+        let get = |name: &str| match name {
+            "__name__" => family,
+            _ => labels.iter().find(|(n, _)| *n == name).map_or("", |(_, v)| *v)
+        };
+        self.0.iter().all(|matcher| matcher.matches(get(matcher.label())))
+    }
+
+    /// Whether this selector looks at the metric name at all.
+    pub fn has_name_matchers(&self) -> bool {
+        self.0.iter().any(|matcher| matcher.label() == "__name__")
+    }
+}
+
+/// Parses a double-quoted string at the start of `s`, returning it unescaped along with whatever comes after it.
+fn parse_quoted(s: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 2..])),
+            '\\' => value.push(match chars.next()?.1 { 'n' => '\n', c => c }),
+            c => value.push(c)
+        }
+    }
+    None
+}
+
+/// Gets all match[] selectors out of a URL query string.
+pub fn selectors_from_query(query: &str) -> Result<Vec<Selector>> {
+    query_params(query, "match[]").map(|value| Selector::parse(&value)).collect()
+}
+
+/// All values of the query parameter `name` in a URL query string, decoded.
+pub fn query_params<'a>(query: &'a str, name: &'a str) -> impl Iterator<Item = String> + 'a {
+    query.split('&')
+        .filter_map(|param| param.split_once('='))
+        .filter(move |(key, _)| percent_decode(key) == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                match std::str::from_utf8(&hex).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(decoded) => out.push(decoded),
+                    None => { out.push(b'%'); out.extend(hex.iter().filter(|b| **b != 0)) }
+                }
+            }
+            b => out.push(b)
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: &[(&str, &str)] = &[("name", "/web-1"), ("image", "nginx:latest")];
+
+    fn matches(selector: &str) -> bool {
+        Selector::parse(selector).unwrap().matches("container_running", LABELS)
+    }
+
+    #[test]
+    fn operators() {
+        assert!(matches(r#"{image="nginx:latest"}"#));
+        assert!(!matches(r#"{image="nginx"}"#));
+        assert!(matches(r#"{image!="nginx"}"#));
+        assert!(!matches(r#"{image!="nginx:latest"}"#));
+        assert!(matches(r#"{name=~"/web-[0-9]+"}"#));
+        assert!(!matches(r#"{name!~"/web-.*"}"#));
+        assert!(matches(r#" { image = "nginx:latest" , name =~ "/web.*" , } "#));
+        assert!(!matches(r#"{image="nginx:latest",name="/db"}"#));
+        assert!(matches("{}"));
+    }
+
+    #[test]
+    fn regexes_are_anchored() {
+        assert!(!matches(r#"{name=~"web"}"#));
+        assert!(!matches(r#"{image=~"nginx"}"#));
+        assert!(matches(r#"{image!~"nginx"}"#));
+        assert!(matches(r#"{image=~"nginx|nginx:latest"}"#));
+    }
+
+    #[test]
+    fn escaped_values() {
+        let labels = [("cmd", "echo \"hi\"\nexit")];
+        let selector = Selector::parse(r#"{cmd="echo \"hi\"\nexit"}"#).unwrap();
+        assert!(selector.matches("container_info", &labels));
+        assert!(Selector::parse(r#"{cmd="back\\slash"}"#).unwrap().matches("container_info", &[("cmd", "back\\slash")]));
+    }
+
+    #[test]
+    fn missing_labels_are_empty() {
+        assert!(matches(r#"{pod_uid=""}"#));
+        assert!(matches(r#"{pod_uid!="abc"}"#));
+        assert!(matches(r#"{pod_uid=~".*"}"#));
+        assert!(!matches(r#"{pod_uid=~".+"}"#));
+    }
+
+    #[test]
+    fn metric_names() {
+        assert!(matches("container_running"));
+        assert!(!matches("up"));
+        assert!(matches(r#"container_running{image="nginx:latest"}"#));
+        assert!(!matches(r#"container_running{image="redis"}"#));
+        assert!(matches(r#"{__name__=~"container_.*"}"#));
+        assert!(!matches(r#"{__name__!="container_running"}"#));
+        assert!(Selector::parse("up").unwrap().has_name_matchers());
+        assert!(!Selector::parse(r#"{image="nginx"}"#).unwrap().has_name_matchers());
+    }
+
+    #[test]
+    fn union_of_match_params() {
+        let selectors = selectors_from_query("match[]=%7Bimage%3D%22redis%22%7D&id=abc&match%5B%5D={name=~\"/web-.%2B\"}").unwrap();
+        assert_eq!(selectors.len(), 2);
+        assert!(!selectors[0].matches("container_running", LABELS));
+        assert!(selectors.iter().any(|s| s.matches("container_running", LABELS)));
+
+        // One selector's labels don't let another one's metric through
+        let selectors = selectors_from_query(r#"match[]=up&match[]={image="redis"}"#).unwrap();
+        assert!(!selectors.iter().any(|s| s.matches("container_running", LABELS)));
+    }
+
+    #[test]
+    fn bad_selectors() {
+        assert!(Selector::parse(r#"{image="nginx}"#).is_err());
+        assert!(Selector::parse(r#"{image="nginx\"}"#).is_err());
+        assert!(Selector::parse(r#"{image="nginx"} x"#).is_err());
+        assert!(Selector::parse(r#"{image="nginx"}}"#).is_err());
+        assert!(Selector::parse(r#"{image="nginx" name="web"}"#).is_err());
+        assert!(Selector::parse(r#"{image=nginx}"#).is_err());
+        assert!(Selector::parse(r#"{image=="nginx"}"#).is_err());
+        assert!(Selector::parse(r#"{image=~"(nginx"}"#).is_err());
+        assert!(Selector::parse(r#"{="nginx"}"#).is_err());
+        assert!(Selector::parse(r#"{image="nginx""#).is_err());
+        assert!(Selector::parse("").is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%7B%22%7d"), "{\"}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(query_params("id=1&match%5B%5D=x&id=2", "id").collect::<Vec<_>>(), ["1", "2"]);
+    }
+}
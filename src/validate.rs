@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+use anyhow::{anyhow, Result};
+
+/// Checks that `output` is valid Prometheus text format (0.0.4), for --validate-output.
+///
+/// This checks the things we could plausibly get wrong: metric and label names, label value quoting, duplicate
+/// labels within a series, duplicate series, samples without a matching # TYPE, and unparseable values.
+pub fn validate_metrics_output(output: &str) -> Result<()> {
+    let mut families: HashSet<&str> = HashSet::new();
+    let mut series: HashSet<String> = HashSet::new();
+
+    for (line_no, line) in output.lines().enumerate() {
+        let line_no = line_no + 1;
+        if line.is_empty() { continue }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("TYPE"), Some(name), Some(metric_type)) => {
+                    if !is_metric_name(name) { return Err(anyhow!("line {line_no}: invalid metric name {name:?}")) }
+                    if !["counter", "gauge", "histogram", "summary", "untyped"].contains(&metric_type) {
+                        return Err(anyhow!("line {line_no}: invalid metric type {metric_type:?}"))
+                    }
+                    if !families.insert(name) { return Err(anyhow!("line {line_no}: duplicate # TYPE for {name}")) }
+                }
+                (Some("HELP"), Some(name), _) if !is_metric_name(name) => {
+                    return Err(anyhow!("line {line_no}: invalid metric name {name:?}"))
+                }
+                _ => () // any other comment is allowed
+            }
+            continue;
+        }
+
+        let name_end = line.find(['{', ' ']).ok_or(anyhow!("line {line_no}: sample has no value"))?;
+        let name = &line[..name_end];
+        if !is_metric_name(name) { return Err(anyhow!("line {line_no}: invalid metric name {name:?}")) }
+        if !families.contains(name) { return Err(anyhow!("line {line_no}: sample for {name} has no # TYPE")) }
+
+        let mut rest = &line[name_end..];
+        let mut labels: Vec<(&str, &str)> = Vec::new();
+        if let Some(after_brace) = rest.strip_prefix('{') {
+            let (parsed, after_labels) = parse_labels(after_brace).map_err(|e| anyhow!("line {line_no}: {e}"))?;
+            labels = parsed;
+            rest = after_labels;
+        }
+
+        let mut label_names = HashSet::new();
+        for (label_name, _) in &labels {
+            if !is_label_name(label_name) { return Err(anyhow!("line {line_no}: invalid label name {label_name:?}")) }
+            if !label_names.insert(*label_name) { return Err(anyhow!("line {line_no}: duplicate label {label_name}")) }
+        }
+
+        let mut fields = rest.split_ascii_whitespace();
+        let value = fields.next().ok_or(anyhow!("line {line_no}: sample has no value"))?;
+        if !matches!(value, "NaN" | "+Inf" | "-Inf") && value.parse::<f64>().is_err() {
+            return Err(anyhow!("line {line_no}: invalid value {value:?}"))
+        }
+        if let Some(timestamp) = fields.next() {
+            timestamp.parse::<i64>().map_err(|_| anyhow!("line {line_no}: invalid timestamp {timestamp:?}"))?;
+        }
+        if fields.next().is_some() { return Err(anyhow!("line {line_no}: trailing garbage after sample")) }
+
+        labels.sort();
+        let series_key = format!("{name}{labels:?}");
+        if !series.insert(series_key) { return Err(anyhow!("line {line_no}: duplicate series for {name}")) }
+    }
+    Ok(())
+}
+
+/// Parses `a="b",c="d"}` into label pairs (values still escaped) and returns whatever comes after the `}`.
+fn parse_labels(mut s: &str) -> Result<(Vec<(&str, &str)>, &str)> {
+    let mut labels = Vec::new();
+    loop {
+        if let Some(rest) = s.strip_prefix('}') { return Ok((labels, rest)) }
+        let (name, rest) = s.split_once("=\"").ok_or(anyhow!("malformed label in {s:?}"))?;
+
+        let mut escaped = false;
+        let mut value_end = None;
+        for (i, c) in rest.char_indices() {
+            match (escaped, c) {
+                (true, '\\' | '"' | 'n') => escaped = false,
+                (true, _) => return Err(anyhow!("invalid escape sequence in value of label {name}")),
+                (false, '\\') => escaped = true,
+                (false, '"') => { value_end = Some(i); break }
+                (false, '\n') => return Err(anyhow!("unescaped newline in value of label {name}")),
+                (false, _) => ()
+            }
+        }
+        let value_end = value_end.ok_or(anyhow!("unterminated value for label {name}"))?;
+        labels.push((name, &rest[..value_end]));
+
+        s = &rest[value_end + 1..];
+        if let Some(rest) = s.strip_prefix(',') { s = rest }
+        else if !s.starts_with('}') { return Err(anyhow!("expected , or }} after label {name}")) }
+    }
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+pub fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    !name.starts_with("__")
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "# HELP container_running Whether it runs\n# TYPE container_running gauge\n";
+
+    fn validate(samples: &str) -> Result<()> {
+        validate_metrics_output(&format!("{HEADER}{samples}"))
+    }
+
+    fn error(samples: &str) -> String {
+        validate(samples).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_samples() {
+        validate("container_running 1\n").unwrap();
+        validate("container_running{id=\"a\",name=\"/web\"} 1 1700000000000\n").unwrap();
+        validate("container_running{cmd=\"a \\\"b\\\"\\nc\\\\d} e\"} NaN\n").unwrap();
+        validate("container_running{id=\"a\"} +Inf\ncontainer_running{id=\"b\"} -1.5e3\n").unwrap();
+    }
+
+    #[test]
+    fn duplicate_series() {
+        assert!(error("container_running{id=\"a\"} 1\ncontainer_running{id=\"a\"} 0\n").contains("duplicate series"));
+        // Label order doesn't make it a different series
+        assert!(error("container_running{a=\"1\",b=\"2\"} 1\ncontainer_running{b=\"2\",a=\"1\"} 1\n").contains("duplicate series"));
+    }
+
+    #[test]
+    fn duplicate_label() {
+        assert!(error("container_running{id=\"a\",id=\"b\"} 1\n").contains("duplicate label id"));
+    }
+
+    #[test]
+    fn bad_names() {
+        assert!(error("container-running 1\n").contains("invalid metric name"));
+        assert!(validate_metrics_output("# TYPE 1container gauge\n").unwrap_err().to_string().contains("invalid metric name"));
+        assert!(error("container_running{container.name=\"a\"} 1\n").contains("invalid label name"));
+        assert!(error("container_running{__id=\"a\"} 1\n").contains("invalid label name"));
+    }
+
+    #[test]
+    fn bad_label_values() {
+        assert!(error("container_running{id=\"a\\tb\"} 1\n").contains("invalid escape sequence"));
+        assert!(error("container_running{id=\"a\nb\"} 1\n").contains("unterminated value"));
+        assert!(parse_labels("id=\"a\nb\"}").unwrap_err().to_string().contains("unescaped newline"));
+        assert!(error("container_running{id=\"a\" name=\"b\"} 1\n").contains("expected , or }"));
+    }
+
+    #[test]
+    fn sample_without_type() {
+        assert!(error("container_memory_usage 1\n").contains("has no # TYPE"));
+        assert!(validate_metrics_output("container_running 1\n").unwrap_err().to_string().contains("has no # TYPE"));
+    }
+
+    #[test]
+    fn bad_values_and_timestamps() {
+        assert!(error("container_running\n").contains("has no value"));
+        assert!(error("container_running{id=\"a\"}\n").contains("has no value"));
+        assert!(error("container_running one\n").contains("invalid value"));
+        assert!(error("container_running 1 soon\n").contains("invalid timestamp"));
+        assert!(error("container_running 1 1.5\n").contains("invalid timestamp"));
+        assert!(error("container_running 1 1700000000000 x\n").contains("trailing garbage"));
+    }
+
+    #[test]
+    fn scrape_output_is_valid() {
+        crate::cli::init_test_cfg();
+        let output = crate::metrics::get_metrics_string(None).unwrap();
+        assert!(output.contains("# TYPE dockerprom_build_info gauge"));
+        validate_metrics_output(&output).unwrap();
+    }
+}